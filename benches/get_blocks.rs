@@ -0,0 +1,91 @@
+//! compare serving the blocks of `GetBlocks` as stored on disk with
+//! decoding and encoding them again before sending. Both produce the
+//! bytes of the `Block` messages sent to the peer: the raw path is the
+//! one of `network::query::block_response`, the other one is the
+//! encoding of `Message::Block` with the decoded block.
+//!
+//! The blocks are read from the storage given in `XCHAIN_BENCH_STORAGE`
+//! (`pool-storage` by default), starting from its tip:
+//!
+//!     XCHAIN_BENCH_STORAGE=/path/to/storage cargo +nightly bench --bench get_blocks
+//!
+
+#![feature(test)]
+
+#[macro_use]
+extern crate cbor_event;
+extern crate protocol_tokio as protocol;
+extern crate test;
+extern crate xblockchain;
+extern crate xblockchain_storage;
+
+use std::env;
+use std::path::PathBuf;
+
+use cbor_event::{se::Serializer, Len};
+use protocol::protocol::Response;
+use test::Bencher;
+use xblockchain::block::{Block, HeaderHash};
+use xblockchain_storage::{block_read, tag, Storage, StorageConfig};
+
+/// number of blocks served in each iteration
+const BLOCKS: usize = 100;
+
+fn storage() -> Storage {
+    let path = env::var("XCHAIN_BENCH_STORAGE").unwrap_or("pool-storage".to_owned());
+    Storage::init(&StorageConfig::new(&PathBuf::from(path))).unwrap()
+}
+
+/// the hashes of the last blocks of the chain
+fn last_blocks(storage: &Storage) -> Vec<HeaderHash> {
+    let mut hashes = Vec::new();
+    let mut current = tag::read_hash(storage, &"tip").expect("the storage has no tip");
+    while hashes.len() < BLOCKS {
+        match block_read(storage, &current) {
+            None => break,
+            Some(rblk) => {
+                hashes.push(current);
+                current = rblk.decode().unwrap().get_header().get_previous_header();
+            }
+        }
+    }
+    assert!(!hashes.is_empty(), "the storage has no block");
+    hashes
+}
+
+#[bench]
+fn get_blocks_raw(b: &mut Bencher) {
+    let storage = storage();
+    let hashes = last_blocks(&storage);
+    b.iter(|| {
+        hashes
+            .iter()
+            .map(|hash| {
+                let rblk = block_read(&storage, hash).unwrap();
+                let mut serializer = Serializer::new_vec();
+                serializer
+                    .write_array(Len::Len(2))
+                    .and_then(|s| s.write_unsigned_integer(0))
+                    .and_then(|s| s.write_raw_bytes(rblk.as_ref()))
+                    .unwrap();
+                serializer.finalize().len()
+            })
+            .sum::<usize>()
+    });
+}
+
+#[bench]
+fn get_blocks_decode_encode(b: &mut Bencher) {
+    let storage = storage();
+    let hashes = last_blocks(&storage);
+    b.iter(|| {
+        hashes
+            .iter()
+            .map(|hash| {
+                let block = block_read(&storage, hash).unwrap().decode().unwrap();
+                let response: Response<Block, String> = Response::Ok(block);
+                cbor!(&response).unwrap().len()
+            })
+            .sum::<usize>()
+    });
+}
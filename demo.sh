@@ -18,14 +18,9 @@ else
 			
 			stub='node_'$counter
 			privkey=$stub'.xprv'
-			pubkey=$stub'.xpub'
 
-			echo "Making keys"
-			./genkeypair.sh $privkey $pubkey
-
-			echo "Adding key to config"
-			pubkeycontents=`cat $pubkey` 
-			echo "    - $pubkeycontents" >> $config
+			echo "Making keys and adding the public key to config"
+			cargo run -- keygen generate --secret $privkey >> $config
 		done
 
 		echo "Starting $node_count Nodes"		
@@ -8,8 +8,6 @@ echo
 echo "Xchain - Setup generator"
 echo
 
-clipath=$(which xblockchain-cli)
-
 TEMP=`getopt -o -n:g:c:f: --long nodes:,config::,genesis:,flavour: -n 'setup.sh' -- "$@"`
 eval set -- "$TEMP"
 
@@ -57,15 +55,11 @@ else
     folder="$PWD/${folder#./}"
 fi
 
-# xblockchain-cli - location of xblockchain-cli 
-cli=$clipath
-
 cat << OPTIONS
 Using these options:
 	Folder: $folder
 	Nodes: $nodes
 	Genesis: $genesis
-	CLI: $cli
 	Config: $config
         Flavour: $flavourdesc
 OPTIONS
@@ -95,16 +89,6 @@ else
   exit 1
 fi
 
-if [[ $cli ]]; then
-  if [[ ! -e $cli ]]; then
-    echo "Error: could not read xblockchain-cli at $cli"
-    exit 1
-  fi
-else
-  echo "Error: Please supply a xblockchain-cli in environment"
-  exit 1
-fi
-
 ## we have all the info we need at this point
 #
 ## exit if a command fails
@@ -126,7 +110,6 @@ cp -rv template/* $folder/
 echo
 
 echo "Copying in binaries"
-cp $cli $folder/bin/xblockchain-cli
 cp ../target/debug/xchain $folder/bin/
 echo
 
@@ -149,18 +132,12 @@ for (( counter=1; counter<=$nodes; counter+=1 )); do
   pushd $node_folder > /dev/null
 
   privkey=$node_folder/$stub'.xprv'
-  pubkey=$node_folder/$stub'.xpub'
 
   echo -e "\t Making keys for $stub"
   echo -e "\t\t PRIV = $privkey"
-  echo -e "\t\t PUB  = $pubkey"
-
-  ../../bin/xblockchain-cli debug generate-xprv $privkey
-  ../../bin/xblockchain-cli debug xprv-to-xpub $privkey $pubkey
 
   echo -e "\t Adding $stub public key to global config"
-  pubkeycontents=`cat $pubkey`
-  echo "    - $pubkeycontents" >> $folder'/config.yaml'
+  ../../bin/xchain keygen generate --secret $privkey >> $folder'/config.yaml'
     
   echo -e "\t Adding $stub private key to genesis file"
   privkeycontents=`cat $privkey`
@@ -1,14 +1,11 @@
 #!/bin/bash
 
-# genkeypair - uses xblockchain-cli to create public and private keys
-CLI='./xblockchain-cli'
+# genkeypair - uses xchain to create a secret key and print its public key
+XCHAIN='./xchain'
 
-# params - priv and pub key names
+# params - secret key name
 PRIVKEY=${1:-'key.xprv'}
-PUBKEY=${2:-'key.xpub'}
 
 echo "PRIV = $PRIVKEY"
-echo "PUB  = $PUBKEY"
 
-$CLI debug generate-xprv $PRIVKEY
-$CLI debug xprv-to-xpub $PRIVKEY $PUBKEY
+$XCHAIN keygen generate --secret $PRIVKEY
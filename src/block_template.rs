@@ -0,0 +1,429 @@
+//! block template assembly
+//!
+//! When the node leads a slot, the assembler selects the transactions
+//! of the pool to put in the block. The candidates are ordered by fee
+//! density (fee per byte), then by arrival time in the pool, then by
+//! id, so the template only depends on the content of the pool and the
+//! chain state at the tip.
+//!
+//! The candidates are taken in this order as long as they are valid on
+//! top of the tip and of the transactions already selected: their
+//! inputs are unspent, their witnesses match, they pay at least the
+//! fee of the protocol and they fit in the block. A transaction
+//! spending the outputs of another pool transaction waits until its
+//! parent is selected, the selection is repeated until it stops
+//! growing.
+//!
+//! The size limit of the blocks is the `maxBlockSize` of the block
+//! version data of the genesis configuration. The block of the slot is
+//! made of the selected transactions and signed by the leader.
+//!
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    fs, io,
+    path::Path,
+};
+
+use cbor_event::{self, se::Serializer};
+use serde_yaml;
+use xblockchain::block::{
+    normal, update, Block, BlockHeaderAttributes, BlockVersion, ChainDifficulty, ChainState, EpochSlotId,
+    HeaderExtraData, SoftwareVersion,
+};
+use xblockchain::fee::FeeAlgorithm;
+use xblockchain::hash::Blake2b256;
+
+use blockcfg::{Transaction, TransactionId, TxOut, TxoPointer};
+use clock::global::GlobalTime;
+use secrets::NodeSecret;
+use state::ProtocolParameters;
+use transaction::TPool;
+
+/// the signing tag of the main blocks signed by their leader
+const SIGN_MAIN_BLOCK: &[u8] = b"02";
+
+/// the part of the block reserved for the header and the encoding of
+/// the body
+const BLOCK_OVERHEAD: usize = 1024;
+
+/// the transactions selected for a block
+#[derive(Debug, Clone)]
+pub struct BlockTemplate {
+    /// the transactions, a transaction always comes after the ones
+    /// whose outputs it spends
+    pub transactions: Vec<Transaction>,
+    /// the encoded size of the transactions
+    pub size: usize,
+    /// the total of the fees paid by the transactions
+    pub fees: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenesisConfig {
+    block_version_data: BlockVersionData,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BlockVersionData {
+    max_block_size: String,
+}
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn ::std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// read the maximum size of the blocks from the genesis configuration
+/// (JSON, read as YAML)
+pub fn read_max_block_size(genesis_config: &Path) -> io::Result<usize> {
+    let file = fs::File::open(genesis_config)?;
+    let config: GenesisConfig = serde_yaml::from_reader(file).map_err(invalid_data)?;
+    let size = &config.block_version_data.max_block_size;
+    size.parse()
+        .map_err(|err| invalid_data(format!("invalid maxBlockSize {}: {}", size, err)))
+}
+
+struct Candidate<Id, Tx> {
+    id: Id,
+    tx: Tx,
+    received: GlobalTime,
+    size: usize,
+    fee: u64,
+}
+
+impl<Id: Ord, Tx> Candidate<Id, Tx> {
+    /// the highest fee density first, then the oldest
+    fn priority(&self, other: &Self) -> Ordering {
+        let density = (other.fee as u128 * self.size as u128).cmp(&(self.fee as u128 * other.size as u128));
+        density
+            .then_with(|| self.received.cmp(&other.received))
+            .then_with(|| self.id.cmp(&other.id))
+    }
+}
+
+/// why a candidate is not selected
+enum Rejection {
+    /// an input is the output of a pool transaction not selected yet
+    Waiting,
+    Invalid(String),
+}
+
+fn encoded_size(tx: &Transaction) -> Option<usize> {
+    let mut serializer = Serializer::new_vec();
+    serializer.serialize(tx).ok()?;
+    Some(serializer.finalize().len())
+}
+
+fn encode<T: cbor_event::se::Serialize>(value: &T) -> Vec<u8> {
+    let mut serializer = Serializer::new_vec();
+    serializer.serialize(value).expect("serialization in memory cannot fail");
+    serializer.finalize()
+}
+
+/// the outputs spent by the transaction, from the chain state or from
+/// the other transactions of the pool
+fn spent_outputs<'a>(
+    tx: &Transaction,
+    chain_state: &'a ChainState,
+    pool_outputs: &BTreeMap<TxoPointer, &'a TxOut>,
+) -> Option<Vec<&'a TxOut>> {
+    tx.tx
+        .inputs
+        .iter()
+        .map(|input| chain_state.utxos.get(input).or_else(|| pool_outputs.get(input).cloned()))
+        .collect()
+}
+
+/// the fee paid by the transaction, `None` if its outputs exceed its
+/// inputs
+fn paid_fee(tx: &Transaction, spent: &[&TxOut]) -> Option<u64> {
+    let inputs = spent.iter().map(|output| u64::from(output.value)).sum::<u64>();
+    let outputs = tx.tx.outputs.iter().map(|output| u64::from(output.value)).sum::<u64>();
+    inputs.checked_sub(outputs)
+}
+
+/// check the witnesses of the transaction against the outputs it
+/// spends, in the order of its inputs, and that it pays the fee of the
+/// protocol. Also used for the transactions entering the pool.
+pub fn verify_transaction(tx: &Transaction, spent: &[&TxOut], parameters: &ProtocolParameters) -> Result<(), String> {
+    if tx.witness.len() != tx.tx.inputs.len() || spent.len() != tx.tx.inputs.len() {
+        return Err("one witness per input expected".to_string());
+    }
+    for (witness, output) in tx.witness.iter().zip(spent.iter()) {
+        if !witness.verify_address(&output.address) {
+            return Err("witness does not match the spent address".to_string());
+        }
+        if !witness.verify_tx(parameters.protocol_magic, &tx.tx) {
+            return Err("invalid witness signature".to_string());
+        }
+    }
+    let fee = paid_fee(tx, spent).ok_or_else(|| "the outputs exceed the inputs".to_string())?;
+    let minimum = parameters
+        .fee_policy
+        .calculate_for_txaux(tx)
+        .map_err(|err| format!("cannot compute the fee: {:?}", err))?;
+    if fee < u64::from(minimum.to_coin()) {
+        return Err(format!("fee {} below the minimum {}", fee, u64::from(minimum.to_coin())));
+    }
+    Ok(())
+}
+
+struct Selection<'a, 'b> {
+    chain_state: &'a ChainState,
+    parameters: &'b ProtocolParameters,
+    /// the outputs of all the pool transactions
+    pool_outputs: BTreeMap<TxoPointer, &'a TxOut>,
+    /// the outputs of the selected transactions
+    created: BTreeMap<TxoPointer, &'a TxOut>,
+    spent: BTreeSet<TxoPointer>,
+    template: BlockTemplate,
+}
+
+impl<'a, 'b> Selection<'a, 'b> {
+    fn check(&self, candidate: &Candidate<TransactionId, &'a Transaction>) -> Result<(), Rejection> {
+        let tx = candidate.tx;
+        let mut spent = Vec::with_capacity(tx.tx.inputs.len());
+        for input in tx.tx.inputs.iter() {
+            if self.spent.contains(input) {
+                return Err(Rejection::Invalid(format!("input {:?} already spent in the block", input)));
+            }
+            match self.chain_state.utxos.get(input).or_else(|| self.created.get(input).cloned()) {
+                Some(output) => spent.push(output),
+                None if self.pool_outputs.contains_key(input) => return Err(Rejection::Waiting),
+                None => return Err(Rejection::Invalid(format!("input {:?} is not unspent", input))),
+            }
+        }
+        verify_transaction(tx, &spent, self.parameters).map_err(Rejection::Invalid)
+    }
+
+    fn select(&mut self, candidate: &Candidate<TransactionId, &'a Transaction>) {
+        let tx = candidate.tx;
+        for input in tx.tx.inputs.iter() {
+            self.created.remove(input);
+            self.spent.insert(input.clone());
+        }
+        for (index, output) in tx.tx.outputs.iter().enumerate() {
+            self.created.insert(TxoPointer::new(candidate.id.clone(), index as u32), output);
+        }
+        self.template.transactions.push(tx.clone());
+        self.template.size += candidate.size;
+        self.template.fees += candidate.fee;
+    }
+}
+
+/// select the transactions of the pool for a block on top of the
+/// given chain state, within `max_size` bytes for the whole block.
+pub fn assemble(
+    tpool: &TPool<TransactionId, Transaction>,
+    chain_state: &ChainState,
+    parameters: &ProtocolParameters,
+    max_size: usize,
+) -> BlockTemplate {
+    let max_size = max_size.saturating_sub(BLOCK_OVERHEAD);
+
+    let mut pool_outputs = BTreeMap::new();
+    for (id, (_, tx)) in tpool.content.iter() {
+        for (index, output) in tx.tx.outputs.iter().enumerate() {
+            pool_outputs.insert(TxoPointer::new(id.clone(), index as u32), output);
+        }
+    }
+
+    let mut candidates = Vec::with_capacity(tpool.content.len());
+    for (id, (received, tx)) in tpool.content.iter() {
+        let size = match encoded_size(tx) {
+            Some(size) => size,
+            None => {
+                debug!("transaction {} cannot be encoded, skipped", id);
+                continue;
+            }
+        };
+        let fee = match spent_outputs(tx, chain_state, &pool_outputs).and_then(|spent| paid_fee(tx, &spent)) {
+            Some(fee) => fee,
+            None => {
+                debug!("transaction {} spends unknown outputs or more than its inputs, skipped", id);
+                continue;
+            }
+        };
+        candidates.push(Candidate {
+            id: id.clone(),
+            tx: tx,
+            received: *received,
+            size: size,
+            fee: fee,
+        });
+    }
+
+    let mut selection = Selection {
+        chain_state: chain_state,
+        parameters: parameters,
+        pool_outputs: pool_outputs,
+        created: BTreeMap::new(),
+        spent: BTreeSet::new(),
+        template: BlockTemplate {
+            transactions: Vec::new(),
+            size: 0,
+            fees: 0,
+        },
+    };
+    select_in_order(candidates, max_size, |candidate| {
+        selection.check(candidate)?;
+        selection.select(candidate);
+        Ok(())
+    });
+    selection.template
+}
+
+/// take the candidates by priority as long as they fit in `max_size`
+/// bytes and `try_select` selects them. The candidates waiting for the
+/// selection of a parent are tried again while the selection grows.
+fn select_in_order<Id, Tx, F>(mut candidates: Vec<Candidate<Id, Tx>>, max_size: usize, mut try_select: F)
+where
+    Id: Ord + Display,
+    F: FnMut(&Candidate<Id, Tx>) -> Result<(), Rejection>,
+{
+    candidates.sort_by(|a, b| a.priority(b));
+    let mut size = 0;
+    loop {
+        let mut waiting = Vec::new();
+        let mut progress = false;
+        for candidate in candidates {
+            if size + candidate.size > max_size {
+                debug!("transaction {} not selected: the block is full", candidate.id);
+                continue;
+            }
+            match try_select(&candidate) {
+                Ok(()) => {
+                    size += candidate.size;
+                    progress = true;
+                }
+                Err(Rejection::Waiting) => waiting.push(candidate),
+                Err(Rejection::Invalid(reason)) => debug!("transaction {} not selected: {}", candidate.id, reason),
+            }
+        }
+        if !progress || waiting.is_empty() {
+            break;
+        }
+        candidates = waiting;
+    }
+}
+
+/// the block of the slot on top of the chain state, made of the
+/// transactions of the template and signed by the leader
+pub fn make_block(
+    template: BlockTemplate,
+    chain_state: &ChainState,
+    parameters: &ProtocolParameters,
+    slot: EpochSlotId,
+    leader: &NodeSecret,
+) -> Block {
+    let body = normal::Body {
+        tx: normal::TxPayload::new(template.transactions),
+        ssc: normal::SscPayload::CertificatesPayload(normal::VssCertificates::new(Vec::new())),
+        delegation: normal::DlgPayload(cbor_event::Value::Array(Vec::new())),
+        update: update::UpdatePayload {
+            proposal: None,
+            votes: Vec::new(),
+        },
+    };
+    let attributes = BlockHeaderAttributes(cbor_event::Value::Object(BTreeMap::new()));
+    let extra_data = HeaderExtraData {
+        block_version: BlockVersion::new(0, 0, 0),
+        software_version: SoftwareVersion::new(env!("CARGO_PKG_NAME"), 1).expect("valid software version"),
+        extra_data_proof: Blake2b256::new(&encode(&attributes)),
+        attributes: attributes,
+    };
+    let mut header = normal::BlockHeader {
+        protocol_magic: parameters.protocol_magic,
+        previous_header: chain_state.last_block.clone(),
+        body_proof: normal::BodyProof::generate_from_body(&body),
+        consensus: normal::Consensus {
+            slot_id: slot,
+            leader_key: leader.public().clone(),
+            chain_difficulty: ChainDifficulty::from(chain_state.chain_length + 1),
+            block_signature: normal::BlockSignature::ProxyLight(Vec::new()),
+        },
+        extra_data: extra_data,
+    };
+
+    // the leader signs the protocol magic and the content of the header
+    let mut data = SIGN_MAIN_BLOCK.to_vec();
+    data.extend(encode(&parameters.protocol_magic));
+    data.extend(encode(&normal::MainToSign::from_header(&header)));
+    header.consensus.block_signature = normal::BlockSignature::Signature(leader.sign(&data));
+
+    Block::MainBlock(normal::Block {
+        header: header,
+        body: body,
+        extra: cbor_event::Value::Array(Vec::new()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn candidate(id: u32, size: usize, fee: u64, received: u64) -> Candidate<u32, ()> {
+        Candidate {
+            id: id,
+            tx: (),
+            received: GlobalTime::from(UNIX_EPOCH + Duration::from_secs(received)),
+            size: size,
+            fee: fee,
+        }
+    }
+
+    /// the ids selected in order, a `(child, parent)` dependency makes
+    /// the child wait until the parent is selected
+    fn select(candidates: Vec<Candidate<u32, ()>>, max_size: usize, parents: &[(u32, u32)]) -> Vec<u32> {
+        let mut selected = Vec::new();
+        select_in_order(candidates, max_size, |candidate| {
+            let waiting = parents
+                .iter()
+                .any(|(child, parent)| *child == candidate.id && !selected.contains(parent));
+            if waiting {
+                return Err(Rejection::Waiting);
+            }
+            selected.push(candidate.id);
+            Ok(())
+        });
+        selected
+    }
+
+    #[test]
+    fn the_highest_fee_density_comes_first() {
+        let candidates = vec![candidate(1, 100, 100, 0), candidate(2, 100, 300, 0), candidate(3, 50, 100, 0)];
+        assert_eq!(select(candidates, 1000, &[]), vec![2, 3, 1]);
+    }
+
+    #[test]
+    fn the_ties_are_broken_by_arrival_then_id() {
+        let candidates = vec![candidate(1, 100, 200, 5), candidate(3, 100, 200, 3), candidate(2, 50, 100, 3)];
+        assert_eq!(select(candidates, 1000, &[]), vec![2, 3, 1]);
+    }
+
+    #[test]
+    fn a_parent_is_selected_before_its_child() {
+        let candidates = vec![candidate(1, 100, 100, 0), candidate(2, 100, 500, 0)];
+        assert_eq!(select(candidates, 1000, &[(2, 1)]), vec![1, 2]);
+    }
+
+    #[test]
+    fn the_candidates_beyond_the_size_limit_are_skipped() {
+        let candidates = vec![
+            candidate(1, 60, 600, 0),
+            candidate(2, 50, 450, 0),
+            candidate(3, 30, 240, 0),
+            // waits for a parent which does not fit
+            candidate(4, 10, 1000, 0),
+        ];
+        assert_eq!(select(candidates, 100, &[(4, 2)]), vec![1, 3]);
+    }
+}
@@ -5,4 +5,9 @@ pub type TransactionId = xblockchain::tx::TxId;
 pub type Transaction = xblockchain::tx::TxAux;
 pub type BlockHash = xblockchain::block::HeaderHash;
 pub type Block = xblockchain::block::Block;
+/// a block as stored on disk and sent on the network, in CBOR
+pub type RawBlock = xblockchain::block::RawBlock;
 pub type Header = xblockchain::block::BlockHeader;
+pub type TxoPointer = xblockchain::tx::TxoPointer;
+pub type TxOut = xblockchain::tx::TxOut;
+pub type Address = xblockchain::address::ExtendedAddr;
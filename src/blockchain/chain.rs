@@ -1,12 +1,22 @@
 use std::sync::{Arc, RwLock};
 use std::collections::BTreeMap;
 
+use futures::sync::mpsc;
 use xblockchain_storage::StorageConfig;
 use xblockchain_storage::{tag, Storage, blob, block_read};
 use xblockchain_storage::chain_state::restore_chain_state;
 use xblockchain::block::{ChainState, Block, BlockDate};
 
 use super::super::blockcfg::{GenesisData, BlockHash};
+use super::super::state::{apply_block_unverified, SnapshotWriter, Snapshots, State, Utxos};
+use super::main_chain::MainChain;
+
+/// a snapshot of the state is written every this number of blocks
+const SNAPSHOT_INTERVAL: u64 = 1000;
+
+/// maximum number of blocks replayed on top of a snapshot on startup,
+/// beyond this the chain state is restored from the storage
+const MAX_SNAPSHOT_REPLAY: usize = 10 * SNAPSHOT_INTERVAL as usize;
 
 #[allow(dead_code)]
 pub struct Blockchain {
@@ -18,30 +28,92 @@ pub struct Blockchain {
     /// The current chain state corresponding to our tip.
     chain_state: ChainState,
 
+    /// the blocks of the chain ending at our tip, by height
+    main_chain: MainChain<BlockHash>,
+
     /// Incoming blocks whose parent does not exist yet. Sorted by
     /// parent hash to allow quick look up of the children of a
     /// parent.
     ///
     /// FIXME: need some way to GC unconnected blocks after a while.
     unconnected_blocks: BTreeMap<BlockHash, BTreeMap<BlockHash, Block>>,
+
+    /// where the snapshots of the state are written
+    snapshots: Snapshots,
+    snapshot_writer: SnapshotWriter,
+
+    /// the tasks following the changes of our tip
+    tip_subscribers: Vec<mpsc::UnboundedSender<BlockHash>>,
 }
 
 pub type BlockchainR = Arc<RwLock<Blockchain>>;
 
+/// the result of the processing of an incoming block
+#[derive(Debug)]
+pub enum BlockStatus {
+    /// the block is connected to our chain, it may or may not be
+    /// our new tip
+    Connected,
+    /// the parent of the block is unknown, the block is kept until
+    /// the parent is received
+    Unconnected,
+    /// the block, or the chain it is on, failed the verification
+    Invalid(String),
+}
+
 // FIXME: copied from xblockchain-cli
 pub const LOCAL_BLOCKCHAIN_TIP_TAG : &'static str = "tip";
 
 impl Blockchain {
-    pub fn from_storage(genesis_data: GenesisData, storage_config: &StorageConfig) -> Self {
+    pub fn from_storage(genesis_data: GenesisData, storage_config: &StorageConfig, snapshots: Snapshots) -> Self {
         let storage = Storage::init(storage_config).unwrap();
         let tip = tag::read_hash(&storage, &LOCAL_BLOCKCHAIN_TIP_TAG).unwrap_or(genesis_data.genesis_prev.clone());
-        let chain_state = restore_chain_state(&storage, &genesis_data, &tip)
-            .expect("restoring chain state");
+        let chain_state = match load_chain_state(&storage, &genesis_data, &snapshots, &tip) {
+            Some(chain_state) => chain_state,
+            None => restore_chain_state(&storage, &genesis_data, &tip)
+                .expect("restoring chain state"),
+        };
+        let main_chain = load_main_chain(&storage, &genesis_data.genesis_prev, &snapshots, &tip);
+        let snapshot_writer = SnapshotWriter::spawn(snapshots.clone()).expect("starting the snapshot writer");
         Blockchain {
             genesis_data,
             storage,
             chain_state,
+            main_chain,
             unconnected_blocks: BTreeMap::new(),
+            snapshots,
+            snapshot_writer,
+            tip_subscribers: Vec::new(),
+        }
+    }
+
+    /// follow the changes of our tip: the current tip is sent first,
+    /// then each new tip. The new tip may be on another fork than the
+    /// previous one.
+    pub fn subscribe_tip_changes(&mut self) -> mpsc::UnboundedReceiver<BlockHash> {
+        let (sender, receiver) = mpsc::unbounded();
+        let _ = sender.unbounded_send(self.get_tip());
+        self.tip_subscribers.push(sender);
+        receiver
+    }
+
+    fn notify_tip_change(&mut self) {
+        let tip = self.get_tip();
+        self.tip_subscribers.retain(|sender| sender.unbounded_send(tip.clone()).is_ok());
+    }
+
+    /// the ledger state at our tip
+    pub fn get_state(&self) -> State {
+        State::from_chain_state(&self.genesis_data, &self.chain_state)
+    }
+
+    /// write a snapshot of the state and of the main chain every
+    /// `SNAPSHOT_INTERVAL` blocks. Only the copy of the state is done
+    /// here, it is written by the snapshot writer once the blockchain
+    /// is unlocked.
+    fn snapshot_periodically(&self) {
+        if self.chain_state.chain_length % SNAPSHOT_INTERVAL == 0 {
+            self.snapshot_writer.write(self.get_state(), self.main_chain.hashes().to_vec());
         }
     }
 
@@ -50,6 +122,30 @@ impl Blockchain {
         self.chain_state.last_block.clone()
     }
 
+    /// the number of blocks of our chain
+    pub fn get_chain_length(&self) -> u64 {
+        self.chain_state.chain_length
+    }
+
+    /// the unspent outputs at our tip
+    pub fn get_utxos(&self) -> &Utxos {
+        &self.chain_state.utxos
+    }
+
+    /// the blocks of the chain ending at our tip, by height
+    pub fn get_main_chain(&self) -> &MainChain<BlockHash> {
+        &self.main_chain
+    }
+
+    /// the chain state at our tip
+    pub fn get_chain_state(&self) -> &ChainState {
+        &self.chain_state
+    }
+
+    pub fn get_genesis_data(&self) -> &GenesisData {
+        &self.genesis_data
+    }
+
     pub fn get_storage(&self) -> &Storage {
         &self.storage
     }
@@ -63,23 +159,27 @@ impl Blockchain {
     /// sollicit its parent. If it is connected and is a longer valid
     /// chain than the current tip, then switch the tip. If it is
     /// connected but is not a longer valid chain, then discard it.
-    pub fn handle_incoming_block(&mut self, block: Block) {
+    pub fn handle_incoming_block(&mut self, block: Block) -> BlockStatus {
 
         let block_hash = block.get_header().compute_hash();
         let parent_hash = block.get_header().get_previous_header();
 
         if self.block_exists(&parent_hash) {
-            self.handle_connected_block(block_hash, block);
+            match self.handle_connected_block(block_hash, block) {
+                Ok(()) => BlockStatus::Connected,
+                Err(err) => BlockStatus::Invalid(err),
+            }
         } else {
             self.sollicit_block(&parent_hash);
             self.unconnected_blocks.entry(parent_hash)
                 .or_insert(BTreeMap::new())
                 .insert(block_hash, block);
+            BlockStatus::Unconnected
         }
     }
 
     /// Handle a block whose ancestors are on disk.
-    fn handle_connected_block(&mut self, block_hash: BlockHash, block: Block) {
+    fn handle_connected_block(&mut self, block_hash: BlockHash, block: Block) -> Result<(), String> {
 
         // Quick optimization: don't do anything if the incoming block
         // is already the tip. Ideally we would bail out if the
@@ -113,15 +213,23 @@ impl Blockchain {
                         info!("switching to new tip {} ({:?}), previous length {}, new length {}",
                               block_hash, new_chain_state.last_date,
                               self.chain_state.chain_length, new_chain_state.chain_length);
+                        self.update_main_chain(&block_hash, block.get_header().get_previous_header());
                         self.chain_state = new_chain_state;
                         tag::write_hash(&self.storage, &LOCAL_BLOCKCHAIN_TIP_TAG, &block_hash);
+                        self.snapshot_periodically();
+                        self.notify_tip_change();
                     } else {
                         info!("discarding shorter incoming fork {} ({:?}, length {}), tip length {}",
                               block_hash, new_chain_state.last_date,
                               new_chain_state.chain_length, self.chain_state.chain_length);
                     }
                 }
-                Err(err) => error!("cannot compute chain state for incoming fork {}: {:?}", block_hash, err)
+                Err(err) => {
+                    error!("cannot compute chain state for incoming fork {}: {:?}", block_hash, err);
+                    // the children of an invalid block are invalid too
+                    self.unconnected_blocks.remove(&block_hash);
+                    return Err(format!("{:?}", err));
+                }
             }
         }
 
@@ -129,12 +237,70 @@ impl Blockchain {
         if let Some(children) = self.unconnected_blocks.remove(&block_hash) {
             for (child_hash, child_block) in children {
                 info!("triggering child block {}", child_hash);
-                self.handle_connected_block(child_hash, child_block);
+                if let Err(err) = self.handle_connected_block(child_hash.clone(), child_block) {
+                    warn!("child block {} is invalid: {}", child_hash, err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a block of the history below a trusted checkpoint. The
+    /// caller checked the block is on the chain of headers ending at
+    /// the checkpoint, so the block is authenticated by its hash and
+    /// its body proof: it is applied without the verification and extends our tip regardless
+    /// of the length of the chain. The tip is left unchanged if the
+    /// block cannot be applied.
+    pub fn handle_trusted_block(&mut self, block: Block) -> Result<(), String> {
+        let block_hash = block.get_header().compute_hash();
+        if block.get_header().get_previous_header() != self.chain_state.last_block {
+            return Err(format!("trusted block {} does not extend our tip {}",
+                               block_hash, self.chain_state.last_block));
+        }
+
+        blob::write(&self.storage, &block_hash, cbor!(block).unwrap().as_ref())
+            .expect("unable to write block to disk");
+        apply_block_unverified(&mut self.chain_state, &block_hash, &block)
+            .map_err(|err| format!("cannot apply the trusted block {}: {}", block_hash, err))?;
+        self.main_chain.push(block_hash.clone());
+        tag::write_hash(&self.storage, &LOCAL_BLOCKCHAIN_TIP_TAG, &block_hash);
+        self.snapshot_periodically();
+        self.notify_tip_change();
+        Ok(())
+    }
+
+    /// move the main chain to the new tip: the blocks of the new fork
+    /// are found walking back from the tip to the main chain.
+    fn update_main_chain(&mut self, tip: &BlockHash, parent: BlockHash) {
+        let mut fork = vec![tip.clone()];
+        let mut current = parent;
+        let height = loop {
+            if let Some(height) = self.main_chain.height(&current) {
+                break height;
             }
+            let parent = block_read(&self.storage, &current)
+                .and_then(|rblk| rblk.decode().ok())
+                .map(|block| block.get_header().get_previous_header())
+                .expect("the ancestors of a stored block are stored");
+            fork.push(current);
+            current = parent;
+        };
+        fork.reverse();
+        self.main_chain.switch(height, fork);
+    }
+
+    /// write the tip tag and a snapshot of the state, done on shutdown
+    /// so the next start resumes from our latest tip.
+    pub fn flush(&self) {
+        tag::write_hash(&self.storage, &LOCAL_BLOCKCHAIN_TIP_TAG, &self.chain_state.last_block);
+        let state = self.get_state();
+        match self.snapshots.write(&state, self.main_chain.hashes()) {
+            Ok(()) => debug!("state snapshot written at {}", state.block),
+            Err(err) => warn!("cannot write the state snapshot at {}: {}", state.block, err),
         }
     }
 
-    fn block_exists(&self, block_hash: &BlockHash) -> bool {
+    pub fn block_exists(&self, block_hash: &BlockHash) -> bool {
         // TODO: we assume as an invariant that if a block exists on
         // disk, its ancestors exist on disk as well. Need to make
         // sure that this invariant is preserved everywhere
@@ -149,3 +315,82 @@ impl Blockchain {
         //unimplemented!();
     }
 }
+
+/// the blocks of the chain from the genesis to `tip`, read walking the
+/// chain back from the tip once on startup.
+fn load_main_chain(storage: &Storage, genesis: &BlockHash, snapshots: &Snapshots, tip: &BlockHash) -> MainChain<BlockHash> {
+    info!("indexing the main chain");
+    // walk back to the latest block with the main chain saved with its
+    // snapshot, or to the genesis
+    let mut blocks = Vec::new();
+    let mut current = tip.clone();
+    let mut main_chain = loop {
+        if current == *genesis {
+            break MainChain::new(genesis.clone());
+        }
+        match snapshots.read_main_chain(&current) {
+            Ok(Some(ref hashes)) if hashes.first() == Some(genesis) && hashes.last() == Some(&current) => {
+                break MainChain::from_hashes(hashes.clone());
+            }
+            Ok(Some(_)) => warn!("the main chain saved at {} does not end at this block", current),
+            Ok(None) => {}
+            Err(err) => warn!("cannot read the main chain saved at {}: {}", current, err),
+        }
+        let parent = block_read(storage, &current)
+            .and_then(|rblk| rblk.decode().ok())
+            .map(|block| block.get_header().get_previous_header())
+            .expect("the ancestors of the tip are stored");
+        blocks.push(current);
+        current = parent;
+    };
+    let height = main_chain.tip_height();
+    main_chain.switch(height, blocks.into_iter().rev().collect());
+    info!("main chain indexed, {} blocks", main_chain.tip_height());
+    main_chain
+}
+
+/// the chain state at `tip`, from the latest snapshot of an ancestor of
+/// `tip` and the blocks following it. `None` if there is no usable
+/// snapshot close enough to `tip`.
+fn load_chain_state(
+    storage: &Storage,
+    genesis_data: &GenesisData,
+    snapshots: &Snapshots,
+    tip: &BlockHash,
+) -> Option<ChainState> {
+    // walk back to the latest snapshot
+    let mut blocks = Vec::new();
+    let mut current = tip.clone();
+    while !snapshots.exists(&current) {
+        if current == genesis_data.genesis_prev || blocks.len() >= MAX_SNAPSHOT_REPLAY {
+            return None;
+        }
+        let block = block_read(storage, &current)?.decode().ok()?;
+        let parent = block.get_header().get_previous_header();
+        blocks.push((current, block));
+        current = parent;
+    }
+
+    let state = match snapshots.read(&current) {
+        Ok(state) => state?,
+        Err(err) => {
+            warn!("cannot read the state snapshot at {}: {}", current, err);
+            return None;
+        }
+    };
+    let mut chain_state = match state.to_chain_state(genesis_data) {
+        Some(chain_state) => chain_state,
+        None => {
+            warn!("the state snapshot at {} does not match the genesis data", current);
+            return None;
+        }
+    };
+    info!("state loaded from the snapshot at {}, replaying {} blocks", current, blocks.len());
+    for (hash, block) in blocks.into_iter().rev() {
+        if let Err(err) = chain_state.verify_block(&hash, &block) {
+            warn!("cannot replay block {} on the state snapshot: {:?}", hash, err);
+            return None;
+        }
+    }
+    Some(chain_state)
+}
@@ -0,0 +1,154 @@
+//! the blocks of our main chain by height
+//!
+//! The blocks only link to their parent, so finding the block at a
+//! given height, or the blocks following a given one, means walking the
+//! chain back from the tip and decoding every block on the way. The
+//! index keeps the hashes of the main chain by height so the headers
+//! and blocks requested by the peers are found directly.
+//!
+//! The height 0 is the genesis hash, which is not a block of the
+//! storage. The index follows the changes of our tip, including the
+//! switches to another fork. It is saved with the state snapshots, on
+//! startup only the blocks above the latest snapshot are walked.
+//!
+
+use std::collections::BTreeMap;
+
+pub struct MainChain<Hash> {
+    /// the hash of the block at each height
+    hashes: Vec<Hash>,
+    heights: BTreeMap<Hash, usize>,
+}
+
+impl<Hash: Ord + Clone> MainChain<Hash> {
+    pub fn new(genesis: Hash) -> Self {
+        let mut heights = BTreeMap::new();
+        heights.insert(genesis.clone(), 0);
+        MainChain {
+            hashes: vec![genesis],
+            heights: heights,
+        }
+    }
+
+    /// the main chain of the given hashes, by height from the genesis
+    pub fn from_hashes(hashes: Vec<Hash>) -> Self {
+        assert!(!hashes.is_empty(), "the main chain starts with the genesis");
+        let heights = hashes.iter().cloned().enumerate().map(|(height, hash)| (hash, height)).collect();
+        MainChain {
+            hashes: hashes,
+            heights: heights,
+        }
+    }
+
+    /// the hashes of the main chain, by height from the genesis
+    pub fn hashes(&self) -> &[Hash] {
+        &self.hashes
+    }
+
+    /// the height of our tip
+    pub fn tip_height(&self) -> usize {
+        self.hashes.len() - 1
+    }
+
+    pub fn tip(&self) -> &Hash {
+        self.hashes.last().expect("the main chain starts with the genesis")
+    }
+
+    /// the height of the block, `None` if it is not on the main chain
+    pub fn height(&self, hash: &Hash) -> Option<usize> {
+        self.heights.get(hash).cloned()
+    }
+
+    /// the hash of the block at the given height
+    pub fn get(&self, height: usize) -> Option<&Hash> {
+        self.hashes.get(height)
+    }
+
+    /// a new tip, child of the current tip
+    pub fn push(&mut self, hash: Hash) {
+        self.heights.insert(hash.clone(), self.hashes.len());
+        self.hashes.push(hash);
+    }
+
+    /// switch to another fork: the blocks above `height` are replaced
+    /// by the given blocks, in the order of the chain.
+    pub fn switch(&mut self, height: usize, blocks: Vec<Hash>) {
+        for hash in self.hashes.drain(height + 1..) {
+            self.heights.remove(&hash);
+        }
+        for hash in blocks {
+            self.push(hash);
+        }
+    }
+
+    /// the height of the newest of the given blocks on the main chain
+    /// at or below `max_height`
+    pub fn newest_ancestor<'a, I>(&self, hashes: I, max_height: usize) -> Option<usize>
+    where
+        I: IntoIterator<Item = &'a Hash>,
+        Hash: 'a,
+    {
+        hashes
+            .into_iter()
+            .filter_map(|hash| self.height(hash))
+            .filter(|height| *height <= max_height)
+            .max()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a chain whose block at height `h` has the hash `h`
+    fn chain(length: u32) -> MainChain<u32> {
+        let mut chain = MainChain::new(0);
+        for hash in 1..=length {
+            chain.push(hash);
+        }
+        chain
+    }
+
+    #[test]
+    fn heights_follow_the_pushed_blocks() {
+        let chain = chain(10);
+        assert_eq!(chain.tip_height(), 10);
+        assert_eq!(*chain.tip(), 10);
+        assert_eq!(chain.height(&0), Some(0));
+        assert_eq!(chain.height(&7), Some(7));
+        assert_eq!(chain.height(&11), None);
+        assert_eq!(chain.get(3), Some(&3));
+        assert_eq!(chain.get(11), None);
+    }
+
+    #[test]
+    fn from_hashes_indexes_the_heights() {
+        let chain = MainChain::from_hashes(chain(10).hashes().to_vec());
+        assert_eq!(chain.tip_height(), 10);
+        assert_eq!(chain.height(&7), Some(7));
+        assert_eq!(chain.get(3), Some(&3));
+    }
+
+    #[test]
+    fn switch_replaces_the_blocks_of_the_old_fork() {
+        let mut chain = chain(10);
+        chain.switch(6, vec![107, 108]);
+        assert_eq!(chain.tip_height(), 8);
+        assert_eq!(*chain.tip(), 108);
+        assert_eq!(chain.height(&6), Some(6));
+        assert_eq!(chain.height(&107), Some(7));
+        for old in 7..=10 {
+            assert_eq!(chain.height(&old), None);
+        }
+    }
+
+    #[test]
+    fn newest_ancestor_ignores_the_blocks_above_the_limit() {
+        let chain = chain(10);
+        assert_eq!(chain.newest_ancestor(&[2, 5, 9], 10), Some(9));
+        assert_eq!(chain.newest_ancestor(&[2, 5, 9], 6), Some(5));
+        assert_eq!(chain.newest_ancestor(&[42, 3], 10), Some(3));
+        assert_eq!(chain.newest_ancestor(&[42], 10), None);
+        assert_eq!(chain.newest_ancestor(&[], 10), None);
+    }
+}
@@ -1,5 +1,7 @@
 mod chain;
+mod main_chain;
 mod process;
 
-pub use self::chain::{Blockchain, BlockchainR};
+pub use self::chain::{Blockchain, BlockchainR, BlockStatus, LOCAL_BLOCKCHAIN_TIP_TAG};
+pub use self::main_chain::MainChain;
 pub use self::process::process;
@@ -1,26 +1,45 @@
-use futures::sync::mpsc::UnboundedSender;
-use super::super::intercom::{BlockMsg, NetworkBroadcastMsg};
-use super::super::leadership::selection;
-use std::sync::Arc;
+use super::super::intercom::{BlockMsg, SyncMsg};
+use super::super::reputation::{Offence, ReputationR};
+use super::super::utils::task::TaskMessageBox;
 
-use super::chain;
+use super::chain::{self, BlockStatus};
 
 pub fn process(
     blockchain: &chain::BlockchainR,
-    selection: &Arc<selection::Selection>,
+    reputation: &ReputationR,
+    sync_box: &TaskMessageBox<SyncMsg>,
     bquery: BlockMsg,
-    network_broadcast: &UnboundedSender<NetworkBroadcastMsg>
 )
 {
     match bquery {
-        BlockMsg::NetworkBlock(block) => {
+        BlockMsg::NetworkBlock(peer, connection, block) => {
             debug!("received block from the network: {:#?}", block);
-            blockchain.write().unwrap().handle_incoming_block(block);
+            let parent = block.get_header().get_previous_header();
+            let status = blockchain.write().unwrap().handle_incoming_block(block);
+            match status {
+                BlockStatus::Connected => {}
+                // the peer may be ahead of us: the block is kept and the
+                // sync task requests its missing ancestors, the peer is
+                // only reported if it cannot provide them
+                BlockStatus::Unconnected => {
+                    let msg = SyncMsg { peer: peer, connection: connection, parent: parent };
+                    if let Err(err) = sync_box.clone().try_send(msg) {
+                        // the ancestors are requested again with the
+                        // next block of the peer
+                        debug!("cannot request the missing ancestors: {}", err);
+                    }
+                }
+                BlockStatus::Invalid(_) => {
+                    reputation.write().unwrap().report(&connection, Offence::InvalidBlock);
+                }
+            }
         }
         BlockMsg::LeadershipBlock(block) => {
             debug!("received block from the leadership: {:#?}", block);
-            blockchain.write().unwrap().handle_incoming_block(block.clone());
-            network_broadcast.unbounded_send(NetworkBroadcastMsg::Block(block)).unwrap();
+            match blockchain.write().unwrap().handle_incoming_block(block) {
+                BlockStatus::Invalid(err) => error!("invalid block from the leadership: {}", err),
+                _ => {}
+            }
         }
     }
 }
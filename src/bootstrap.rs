@@ -0,0 +1,266 @@
+//! # Bootstrap phase
+//!
+//! done at every startup: we need to bootstrap from whatever local state
+//! (including nothing) to the latest network state (or close to latest).
+//! Until this happens the node does not create blocks, the network
+//! connections are only used to download data.
+//!
+//! The bootstrap asks the connected peers for their tip, selects the
+//! one of the longest chain and synchronises up to it (see the `sync` module): the
+//! headers are downloaded from the peer with the best tip, the blocks
+//! from all the peers that have the selected tip.
+//!
+//! This is repeated until our tip is the tip of the network, as the
+//! network may have moved on while we were downloading.
+//!
+//! If the operator gave a trusted checkpoint (`--checkpoint`) the node
+//! first synchronises up to the checkpoint, from the trusted peers
+//! (`--trusted-peers`) only. The blocks below the checkpoint are
+//! authenticated by the chain of headers ending at the checkpoint and
+//! by the body proofs of the headers, their signatures and
+//! transactions are not verified again, which makes the bootstrap of a
+//! new node much faster.
+//!
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::{self, Either, Loop},
+    prelude::*,
+};
+use tokio::timer::Delay;
+
+use blockcfg::{BlockHash, Header};
+use blockchain::BlockchainR;
+use intercom::PeerMsg;
+use network::{ConnectedPeers, PeerHandle};
+use reputation::ReputationR;
+use settings::network::Connection;
+use sync::{request, Synchroniser};
+use utils::task::{run_blocking, Shutdown};
+
+/// the default distance (in blocks) to the tip of the network under
+/// which the node is considered synchronised and may lead slots.
+pub const DEFAULT_SYNC_DISTANCE: usize = 10;
+
+/// how long to wait for a peer to connect before warning the
+/// operator
+const PEER_WAIT: Duration = Duration::from_secs(30);
+
+/// how long to wait before trying again after a failed download
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// how often we check if a peer connected
+const PEER_POLL: Duration = Duration::from_secs(1);
+
+const UNKNOWN_DISTANCE: usize = ::std::usize::MAX;
+
+/// the distance, in blocks, between our tip and the best tip of the
+/// network, shared with the tasks that depend on being synchronised
+/// (e.g. the leadership).
+#[derive(Clone)]
+pub struct SyncStatus(Arc<AtomicUsize>);
+
+impl SyncStatus {
+    pub fn new() -> Self {
+        SyncStatus(Arc::new(AtomicUsize::new(UNKNOWN_DISTANCE)))
+    }
+
+    pub fn set_distance(&self, distance: usize) {
+        self.0.store(distance, Ordering::SeqCst)
+    }
+
+    /// the distance to the tip of the network, `None` if we don't
+    /// know yet
+    pub fn distance(&self) -> Option<usize> {
+        match self.0.load(Ordering::SeqCst) {
+            UNKNOWN_DISTANCE => None,
+            distance => Some(distance),
+        }
+    }
+
+    pub fn is_synced(&self, max_distance: usize) -> bool {
+        self.distance().map(|d| d <= max_distance).unwrap_or(false)
+    }
+}
+
+/// what to do after a step of the bootstrap
+enum Step {
+    /// our tip is the best tip of the network
+    Done,
+    /// go on with the next step
+    Again,
+    /// try again after the delay
+    Retry(Duration),
+}
+
+pub struct Bootstrap {
+    blockchain: BlockchainR,
+    peers: ConnectedPeers,
+    status: SyncStatus,
+    synchroniser: Synchroniser,
+    /// the peers trusted to serve the chain up to the checkpoint
+    trusted_peers: Vec<Connection>,
+    shutdown: Shutdown,
+}
+
+impl Bootstrap {
+    pub fn new(
+        blockchain: BlockchainR,
+        peers: ConnectedPeers,
+        reputation: ReputationR,
+        status: SyncStatus,
+        checkpoint: Option<BlockHash>,
+        trusted_peers: Vec<Connection>,
+        shutdown: Shutdown,
+    ) -> Self {
+        Bootstrap {
+            synchroniser: Synchroniser::new(blockchain.clone(), reputation, status.clone(), checkpoint),
+            blockchain,
+            peers,
+            status,
+            trusted_peers,
+            shutdown,
+        }
+    }
+
+    /// run the bootstrap until our tip is the best tip of the
+    /// connected peers, or the shutdown is requested. Each step runs as
+    /// a blocking section, the retries wait on a timer.
+    pub fn run(self) -> impl Future<Item = (), Error = ()> {
+        let shutdown = self.shutdown.clone();
+        let bootstrap = Arc::new(self);
+        let start = Instant::now();
+        future::loop_fn(false, move |warned| {
+            let bootstrap = bootstrap.clone();
+            run_blocking(move || {
+                let mut warned = warned;
+                let step = bootstrap.step(start, &mut warned);
+                (step, warned)
+            })
+            .and_then(move |(step, warned)| match step {
+                Step::Done => Either::A(future::ok(Loop::Break(()))),
+                Step::Again => Either::A(future::ok(Loop::Continue(warned))),
+                Step::Retry(delay) => Either::B(
+                    Delay::new(Instant::now() + delay)
+                        .map_err(|err| error!("bootstrap timer failed: {}", err))
+                        .map(move |()| Loop::Continue(warned)),
+                ),
+            })
+        })
+        .select(shutdown.wait())
+        .map(|_| ())
+        .map_err(|_| ())
+    }
+
+    /// one step of the bootstrap, `warned` is set once the operator was
+    /// told no peer is connected
+    fn step(&self, start: Instant, warned: &mut bool) -> Step {
+        // without peers we don't know the tip of the network, the node
+        // stays unsynchronised until one connects
+        if self.peers.len() == 0 {
+            if !*warned && start.elapsed() > PEER_WAIT {
+                warn!("no peer to bootstrap from yet, waiting for one to connect");
+                *warned = true;
+            }
+            return Step::Retry(PEER_POLL);
+        }
+
+        if let Some(checkpoint) = self.synchroniser.pending_checkpoint().cloned() {
+            return self.sync_checkpoint(&checkpoint);
+        }
+
+        // the best tip is the one of the longest chain, the dates of
+        // the tips say nothing about the work behind them
+        let tips = self.tips();
+        let best = tips.iter().max_by_key(|(_, tip)| tip.difficulty());
+        let (peer, tip) = match best {
+            None => {
+                warn!("no peer answered with its tip, retrying");
+                return Step::Retry(RETRY_DELAY);
+            }
+            Some((peer, tip)) => (peer.clone(), tip.clone()),
+        };
+        let tip_hash = tip.compute_hash();
+        if self.blockchain.read().unwrap().block_exists(&tip_hash) {
+            info!("bootstrap done, tip {}", self.blockchain.read().unwrap().get_tip());
+            self.status.set_distance(0);
+            return Step::Done;
+        }
+
+        // the blocks can be downloaded from all the peers that have the
+        // selected tip
+        let sources = tips
+            .iter()
+            .filter(|(_, t)| t.compute_hash() == tip_hash)
+            .map(|(p, _)| p.clone())
+            .collect::<Vec<_>>();
+        info!("bootstrapping from {} up to tip {} ({:?}, difficulty {:?}), downloading from {} peers",
+              peer.connection, tip_hash, tip.get_blockdate(), tip.difficulty(), sources.len());
+        match self.synchroniser.sync(&peer, sources, &tip_hash) {
+            Ok(()) => Step::Again,
+            Err(err) => {
+                warn!("bootstrap from {} failed: {}", peer.connection, err);
+                Step::Retry(RETRY_DELAY)
+            }
+        }
+    }
+
+    fn is_trusted(&self, connection: &Connection) -> bool {
+        self.trusted_peers.iter().any(|trusted| match (trusted, connection) {
+            (Connection::Socket(a), Connection::Socket(b)) => a == b,
+            #[cfg(unix)]
+            (Connection::Unix(a), Connection::Unix(b)) => a == b,
+            #[cfg(unix)]
+            _ => false,
+        })
+    }
+
+    /// synchronise up to the trusted checkpoint, from the trusted peers
+    /// only.
+    fn sync_checkpoint(&self, checkpoint: &BlockHash) -> Step {
+        let peers = self
+            .peers
+            .handles()
+            .into_iter()
+            .filter(|peer| self.is_trusted(&peer.connection))
+            .collect::<Vec<_>>();
+        let peer = match peers.first() {
+            None => {
+                info!("waiting for a trusted peer to synchronise up to the checkpoint {}", checkpoint);
+                return Step::Retry(RETRY_DELAY);
+            }
+            Some(peer) => peer.clone(),
+        };
+
+        info!("fast synchronisation up to the checkpoint {} from {} peers", checkpoint, peers.len());
+        match self.synchroniser.sync(&peer, peers, checkpoint) {
+            Ok(()) => Step::Again,
+            Err(err) => {
+                warn!("synchronisation up to the checkpoint failed: {}", err);
+                Step::Retry(RETRY_DELAY)
+            }
+        }
+    }
+
+    /// ask all the connected peers for their tip
+    fn tips(&self) -> Vec<(PeerHandle, Header)> {
+        self.peers
+            .handles()
+            .into_iter()
+            .filter_map(|peer| match request(&peer, PeerMsg::GetBlockTip) {
+                Ok(tip) => Some((peer, tip)),
+                Err(err) => {
+                    debug!("cannot get the tip of {}: {}", peer.connection, err);
+                    None
+                }
+            })
+            .collect()
+    }
+}
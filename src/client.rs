@@ -1,111 +1,558 @@
-use super::{BlockHash};
-use blockcfg::{Header, Block};
-use blockchain::{BlockchainR};
-use xblockchain_storage::{block_read, iter};
+//! client queries: the requests of the connected peers to our node
+//!
+//! The queries are served from a snapshot of the storage: the blocks
+//! don't change once written, so the task reads them with its own
+//! storage handle and only takes the blockchain lock to read the tip
+//! and to find the blocks by height on the main chain. The block
+//! processing is not blocked while a peer downloads a long range of
+//! blocks.
+//!
+//! Each block stream is a future of the runtime serving the blocks in
+//! small batches, so a long download does not delay the other queries,
+//! and the number of blocks a peer can download per second is limited.
+//!
+
+use blockcfg::{BlockHash, Header, RawBlock};
+use blockchain::{Blockchain, BlockchainR, MainChain};
+use index::{Index, IndexR};
+use tx_status::TrackerR;
+use network::PeerId;
+use xblockchain_storage::{block_read, Storage, StorageConfig};
 use intercom::*;
-use std::sync::{mpsc::Receiver};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::{cmp, error, fmt};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use futures::future::{self, Either, Loop};
+use futures::prelude::*;
+use tokio::timer::Delay;
+use utils::task::{run_blocking, yield_now};
+
+/// number of blocks of a stream sent before letting the other tasks run
+const STREAM_BATCH_SIZE: u64 = 32;
+
+/// maximum number of block streams served at the same time
+const MAX_STREAMS: usize = 64;
+
+/// maximum number of block streams served at the same time to a peer
+const MAX_PEER_STREAMS: usize = 4;
+
+/// number of blocks a peer can download per second
+const PEER_BLOCKS_PER_SECOND: u64 = 500;
+
+/// number of blocks a peer can download at once after being idle
+const PEER_BLOCKS_BURST: u64 = 2000;
+
+/// how long a rate limited stream waits before trying again
+const THROTTLE_WAIT: Duration = Duration::from_millis(10);
+
+/// token bucket limiting the number of blocks sent to a peer
+struct RateLimit {
+    tokens: u64,
+    updated: Instant,
+}
+
+impl RateLimit {
+    fn new(now: Instant) -> Self {
+        RateLimit { tokens: PEER_BLOCKS_BURST, updated: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated);
+        let millis = elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64;
+        let tokens = millis * PEER_BLOCKS_PER_SECOND / 1000;
+        // keep the fractions of tokens for the next refill
+        if tokens > 0 {
+            self.tokens = cmp::min(self.tokens + tokens, PEER_BLOCKS_BURST);
+            self.updated = now;
+        }
+    }
+
+    /// take up to `wanted` tokens, returns the number taken
+    fn take(&mut self, now: Instant, wanted: u64) -> u64 {
+        self.refill(now);
+        let taken = cmp::min(self.tokens, wanted);
+        self.tokens -= taken;
+        taken
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens == PEER_BLOCKS_BURST
+    }
+}
+
+/// the number of block streams being served, per peer
+#[derive(Default)]
+struct ActiveStreams {
+    total: usize,
+    per_peer: HashMap<PeerId, usize>,
+}
+
+impl ActiveStreams {
+    /// register a new stream, false if there are too many already
+    fn acquire(&mut self, peer: PeerId) -> bool {
+        let peer_streams = self.per_peer.entry(peer).or_insert(0);
+        if self.total >= MAX_STREAMS || *peer_streams >= MAX_PEER_STREAMS {
+            return false;
+        }
+        *peer_streams += 1;
+        self.total += 1;
+        true
+    }
+
+    fn release(&mut self, peer: PeerId) {
+        self.total -= 1;
+        let remove = match self.per_peer.get_mut(&peer) {
+            Some(peer_streams) => {
+                *peer_streams -= 1;
+                *peer_streams == 0
+            }
+            None => false,
+        };
+        if remove {
+            self.per_peer.remove(&peer);
+        }
+    }
+}
+
+/// a `GetBlocks` query being served. The blocks are found by height on
+/// the main chain as they are sent, the stream fails if our main chain
+/// no longer goes through the blocks sent so far and the last block.
+struct BlockStream {
+    peer: PeerId,
+    /// the height of the next block to send
+    next: usize,
+    /// the block at the height before `next`
+    previous: BlockHash,
+    /// the height of the last block to send
+    last: usize,
+    to: BlockHash,
+    reply: BoxRawStreamReply,
+}
+
+impl BlockStream {
+    /// the stream of the blocks from `from` to `to` (included), both
+    /// on the main chain
+    fn new(
+        main_chain: &MainChain<BlockHash>,
+        peer: PeerId,
+        from: &BlockHash,
+        to: &BlockHash,
+        reply: BoxRawStreamReply,
+    ) -> Result<Self, (Error, BoxRawStreamReply)> {
+        let heights = main_chain.height(from).and_then(|from| main_chain.height(to).map(|to| (from, to)));
+        match heights {
+            // the genesis is not a block
+            Some((first, last)) if first > 0 && first <= last => Ok(BlockStream {
+                peer: peer,
+                next: first,
+                previous: main_chain.get(first - 1).expect("the parent is on the main chain").clone(),
+                last: last,
+                to: to.clone(),
+                reply: reply,
+            }),
+            _ => Err((
+                Error::new(ErrorKind::InvalidRange, format!("cannot iterate from {} to {}", from, to)),
+                reply,
+            )),
+        }
+    }
+
+    /// the hashes of the next `count` blocks, `None` if the main chain
+    /// changed since the stream started
+    fn next_hashes(&self, main_chain: &MainChain<BlockHash>, count: u64) -> Option<Vec<BlockHash>> {
+        if main_chain.get(self.next - 1) != Some(&self.previous) || main_chain.get(self.last) != Some(&self.to) {
+            return None;
+        }
+        let end = cmp::min(self.last + 1, self.next + count as usize);
+        (self.next..end).map(|height| main_chain.get(height).cloned()).collect()
+    }
+
+    /// send up to `count` blocks, returns false once the stream is
+    /// complete.
+    fn serve(&mut self, blockchain: &BlockchainR, storage: &Storage, count: u64) -> bool {
+        let hashes = self.next_hashes(blockchain.read().unwrap().get_main_chain(), count);
+        let hashes = match hashes {
+            Some(hashes) => hashes,
+            None => {
+                self.reply.send_error(Error::new(
+                    ErrorKind::InvalidRange,
+                    format!("the chain to {} changed during the download", self.to),
+                ));
+                self.reply.close();
+                return false;
+            }
+        };
+        for hash in hashes {
+            // the peer is gone, no need to read the remaining blocks
+            if self.reply.is_cancelled() {
+                debug!("block stream to peer {} cancelled", self.peer);
+                return false;
+            }
+            match block_read(storage, &hash) {
+                None => self.reply.send_error(Error::new(
+                    ErrorKind::Corrupt,
+                    format!("Cannot read block '{}'", hash),
+                )),
+                Some(rblk) => self.reply.send(rblk),
+            }
+            self.previous = hash;
+            self.next += 1;
+        }
+        if self.next > self.last {
+            self.reply.close();
+            return false;
+        }
+        true
+    }
+}
+
+/// the client queries task, each `GetBlocks` query is served by its
+/// own future on the runtime.
+pub struct ClientTask {
+    blockchain: BlockchainR,
+    storage: Arc<Storage>,
+    streams: Arc<Mutex<ActiveStreams>>,
+    limits: Arc<Mutex<HashMap<PeerId, RateLimit>>>,
+    /// the address and transaction index, if enabled
+    index: Option<IndexR>,
+    tracker: TrackerR,
+}
+
+impl ClientTask {
+    pub fn new(
+        blockchain: BlockchainR,
+        storage_config: &StorageConfig,
+        index: Option<IndexR>,
+        tracker: TrackerR,
+    ) -> Self {
+        ClientTask {
+            blockchain: blockchain,
+            index: index,
+            tracker: tracker,
+            storage: Arc::new(Storage::init(storage_config).expect("cannot open the storage")),
+            streams: Arc::new(Mutex::new(ActiveStreams::default())),
+            limits: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
 
-pub fn client_task(blockchain: BlockchainR, r: Receiver<ClientMsg>) {
-    loop {
-        let query = r.recv().unwrap();
-        debug!("client query received: {:?}", query);
+    pub fn handle(&self, query: ClientQuery) {
+        debug!("client query received from peer {}: {:?}", query.peer, query.msg);
+        let tip = self.blockchain.read().unwrap().get_tip();
 
-        match query {
+        match query.msg {
             ClientMsg::GetBlockTip(mut handler) =>
-                handler.reply(handle_get_block_tip(&blockchain)),
+                handler.reply(handle_get_block_tip(&self.storage, &tip)),
             ClientMsg::GetBlockHeaders(checkpoints, to, mut handler) =>
-                handler.reply(handle_get_block_headers(&blockchain, checkpoints, to)),
-            ClientMsg::GetBlocks(from, to, mut handler) =>
-                handle_get_blocks(&blockchain, from, to, &mut *handler),
+                handler.reply(handle_get_block_headers(&self.storage, &self.blockchain, checkpoints, to)),
+            ClientMsg::GetUtxos(query, mut handler) =>
+                handler.reply(handle_get_utxos(&self.blockchain.read().unwrap(), query)),
+            ClientMsg::GetTransaction(id, mut handler) => handler.reply(
+                self.with_index(|index| {
+                    index.transaction(&id).cloned().ok_or_else(|| {
+                        Error::new(ErrorKind::NotFound, format!("Cannot find transaction '{}'", id))
+                    })
+                }),
+            ),
+            ClientMsg::GetAddressHistory(address, mut handler) => handler.reply(
+                self.with_index(|index| Ok(index.address_history(&address).to_vec())),
+            ),
+            ClientMsg::GetTransactionStatus(id, mut handler) => handler.reply(
+                self.tracker.read().unwrap().get(&id).ok_or_else(|| {
+                    Error::new(ErrorKind::NotFound, format!("transaction '{}' is not tracked", id))
+                }),
+            ),
+            ClientMsg::SubscribeTransactionStatus(ids, handler) =>
+                self.tracker.write().unwrap().subscribe(ids, handler),
+            ClientMsg::GetBlocks(from, to, mut handler) => {
+                if !self.streams.lock().unwrap().acquire(query.peer) {
+                    handler.send_error(Error::new(ErrorKind::Busy, "too many concurrent block requests"));
+                    handler.close();
+                    return;
+                }
+                let stream = BlockStream::new(
+                    self.blockchain.read().unwrap().get_main_chain(),
+                    query.peer,
+                    &from,
+                    &to,
+                    handler,
+                );
+                match stream {
+                    Ok(stream) => {
+                        tokio::spawn(self.serve_blocks(stream));
+                    }
+                    Err((err, mut handler)) => {
+                        self.streams.lock().unwrap().release(query.peer);
+                        handler.send_error(err);
+                        handler.close();
+                    }
+                }
+            }
+        }
+    }
+
+    fn with_index<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Index) -> Result<T, Error>,
+    {
+        match self.index {
+            None => Err(Error::new(ErrorKind::Unsupported, "the index is not enabled on this node")),
+            Some(ref index) => f(&index.read().unwrap()),
         }
     }
+
+    /// serve the stream in batches, giving the other tasks a chance to
+    /// run between two batches and waiting while the peer is rate
+    /// limited.
+    fn serve_blocks(&self, stream: BlockStream) -> impl Future<Item = (), Error = ()> {
+        let peer = stream.peer;
+        let blockchain = self.blockchain.clone();
+        let storage = self.storage.clone();
+        let limits = self.limits.clone();
+        let streams = self.streams.clone();
+        let done_limits = self.limits.clone();
+
+        future::loop_fn(stream, move |mut stream| {
+            let now = Instant::now();
+            let count = limits
+                .lock()
+                .unwrap()
+                .entry(peer)
+                .or_insert_with(|| RateLimit::new(now))
+                .take(now, STREAM_BATCH_SIZE);
+            let blockchain = blockchain.clone();
+            let storage = storage.clone();
+            run_blocking(move || {
+                let more = count == 0 || stream.serve(&blockchain, &storage, count);
+                (stream, more)
+            })
+            .and_then(move |(stream, more)| {
+                let next = if more { Loop::Continue(stream) } else { Loop::Break(()) };
+                if count == 0 {
+                    Either::A(
+                        Delay::new(Instant::now() + THROTTLE_WAIT)
+                            .map_err(|err| error!("block stream timer failed: {}", err))
+                            .map(move |()| next),
+                    )
+                } else {
+                    Either::B(yield_now().map(move |()| next))
+                }
+            })
+        })
+        .then(move |_| {
+            streams.lock().unwrap().release(peer);
+            let now = Instant::now();
+            done_limits.lock().unwrap().retain(|_, limit| !limit.is_full(now));
+            Ok(())
+        })
+    }
+}
+
+/// read the header of the given block, `None` if the block is not in
+/// the storage. Only the header is decoded, not the whole block.
+fn read_header(storage: &Storage, hash: &BlockHash) -> Result<Option<Header>, Error> {
+    match block_read(storage, hash) {
+        None => Ok(None),
+        Some(rblk) => match rblk.to_header().and_then(|header| header.decode()) {
+            Ok(header) => Ok(Some(header)),
+            Err(err) => Err(Error::new(
+                ErrorKind::Corrupt,
+                format!("cannot decode the header of block '{}': {:?}", hash, err),
+            )),
+        },
+    }
 }
 
 fn handle_get_block_tip(
-    blockchain: &BlockchainR
+    storage: &Storage,
+    tip: &BlockHash,
 ) -> Result<Header, Error> {
-    let blockchain = blockchain.read().unwrap();
-    let tip = blockchain.get_tip();
-    match block_read(blockchain.get_storage(), &tip) {
-        None => {
-            Err(format!("Cannot read block '{}'", tip).into())
-        }
-        Some(rblk) => {
-            let blk = rblk.decode().unwrap();
-            Ok(blk.get_header())
+    match read_header(storage, tip)? {
+        None => Err(Error::new(ErrorKind::NotFound, format!("Cannot read block '{}'", tip))),
+        Some(header) => Ok(header),
+    }
+}
+
+/// maximum number of unspent outputs sent in one reply
+const MAX_UTXOS: usize = 10_000;
+
+/// maximum number of unspent outputs a lookup by address scans, the
+/// blockchain is locked meanwhile
+const MAX_UTXO_SCAN: usize = 1_000_000;
+
+/// look up the unspent outputs at the tip. The blockchain is locked
+/// for the whole lookup so the outputs are consistent with the tip.
+/// A lookup by address scans the whole set of unspent outputs, it is
+/// refused if the set is too large.
+fn handle_get_utxos(blockchain: &Blockchain, query: UtxoQuery) -> Result<Utxos, Error> {
+    let utxos = blockchain.get_utxos();
+    let outputs = match query {
+        UtxoQuery::Addresses(_) if utxos.len() > MAX_UTXO_SCAN => {
+            return Err(Error::new(
+                ErrorKind::ResourceExhausted,
+                format!("cannot scan more than {} unspent outputs by address", MAX_UTXO_SCAN),
+            ));
         }
+        UtxoQuery::Addresses(addresses) => utxos
+            .iter()
+            .filter(|(_, output)| addresses.contains(&output.address))
+            .map(|(txo, output)| (txo.clone(), output.clone()))
+            .take(MAX_UTXOS + 1)
+            .collect::<Vec<_>>(),
+        UtxoQuery::OutputRefs(txos) => txos
+            .into_iter()
+            .filter_map(|txo| utxos.get(&txo).cloned().map(|output| (txo, output)))
+            .take(MAX_UTXOS + 1)
+            .collect::<Vec<_>>(),
+    };
+    if outputs.len() > MAX_UTXOS {
+        return Err(Error::new(
+            ErrorKind::ResourceExhausted,
+            format!("more than {} unspent outputs requested", MAX_UTXOS),
+        ));
     }
+    Ok(Utxos {
+        tip: blockchain.get_tip(),
+        outputs: outputs,
+    })
 }
 
+/// maximum number of headers sent in one reply, the peer asks for
+/// the next ones with the last header it received as checkpoint.
 const MAX_HEADERS: usize = 2000;
 
+/// the reasons a `GetBlockHeaders` request cannot be served
+#[derive(Debug)]
+pub enum GetBlockHeadersError<Hash = BlockHash> {
+    /// the requested tip is not a block of our chain
+    UnknownTo(Hash),
+    /// none of the checkpoints is an ancestor of the requested tip
+    NoCommonAncestor,
+}
+
+impl<Hash: fmt::Display> fmt::Display for GetBlockHeadersError<Hash> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GetBlockHeadersError::UnknownTo(to) => write!(f, "block {} is not on our chain", to),
+            GetBlockHeadersError::NoCommonAncestor => {
+                write!(f, "none of the checkpoints is an ancestor of the requested block")
+            }
+        }
+    }
+}
+
+impl<Hash: fmt::Debug + fmt::Display> error::Error for GetBlockHeadersError<Hash> {}
+
+/// the heights of the headers following the newest checkpoint that is
+/// an ancestor of `to`, up to `to`. At most `MAX_HEADERS` heights, the
+/// oldest ones.
+fn header_heights<Hash: Ord + Clone>(
+    main_chain: &MainChain<Hash>,
+    checkpoints: &[Hash],
+    to: &Hash,
+) -> Result<Range<usize>, GetBlockHeadersError<Hash>> {
+    let to_height = main_chain
+        .height(to)
+        .ok_or_else(|| GetBlockHeadersError::UnknownTo(to.clone()))?;
+    let from = main_chain
+        .newest_ancestor(checkpoints, to_height)
+        .ok_or(GetBlockHeadersError::NoCommonAncestor)?;
+    Ok(from + 1..cmp::min(to_height, from + MAX_HEADERS) + 1)
+}
+
+/// send the headers following the newest checkpoint that is an
+/// ancestor of `to`, up to `to`. The checkpoints may include the
+/// genesis hash to download the chain from scratch. At most
+/// `MAX_HEADERS` are sent, the oldest first.
 fn handle_get_block_headers(
+    storage: &Storage,
     blockchain: &BlockchainR,
     checkpoints: Vec<BlockHash>,
     to: BlockHash
 ) -> Result<Vec<Header>, Error> {
-    let blockchain = blockchain.read().unwrap();
-
-    /* Filter out the checkpoints that don't exist and sort them by
-     * block date. */
-    let mut checkpoints = checkpoints.iter().filter_map(
-        |checkpoint|
-        match block_read(blockchain.get_storage(), &checkpoint) {
-            None => None,
-            Some(rblk) => Some((rblk.decode().unwrap().get_header().get_blockdate(), checkpoint))
-        }
-    ).collect::<Vec<_>>();
-
-    if !checkpoints.is_empty() {
-
-        /* Start at the newest checkpoint. */
-        checkpoints.sort_unstable();
-        let from = checkpoints.last().unwrap().1;
-
-        // FIXME: handle checkpoint == genesis
-
-        /* Send headers up to the maximum. Skip the first block since
-         * the range is exclusive of 'from'. */
-        let mut skip = true;
-        let mut headers = vec![];
-        let mut err = None;
-        for x in iter::Iter::new(&blockchain.get_storage(), from.clone(), to).unwrap() {
-            match x {
-                Err(err2) => { err = Some(err2); },
-                Ok((rblk, blk)) => {
-                    if skip {
-                        skip = false;
-                    } else {
-                        headers.push(blk.get_header());
-                        if headers.len() >= MAX_HEADERS { break; }
-                    }
-                }
-            };
-        }
+    let hashes = {
+        let blockchain = blockchain.read().unwrap();
+        let main_chain = blockchain.get_main_chain();
+        let heights = header_heights(main_chain, &checkpoints, &to).map_err(|err| match err {
+            GetBlockHeadersError::UnknownTo(_) => Error::new(ErrorKind::NotFound, err),
+            GetBlockHeadersError::NoCommonAncestor => Error::new(ErrorKind::InvalidRange, err),
+        })?;
+        heights
+            .map(|height| main_chain.get(height).expect("the heights are on the main chain").clone())
+            .collect::<Vec<_>>()
+    };
+
+    hashes
+        .iter()
+        .map(|hash| {
+            // the blocks of the main chain are stored
+            read_header(storage, hash)?.ok_or_else(|| {
+                Error::new(ErrorKind::Corrupt, format!("Cannot read block '{}'", hash))
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        match err {
-            Some(err) => Err(Error::from_error(err)),
-            None => Ok(headers),
+    /// a chain whose block at height `h` has the hash `h`
+    fn chain(length: u32) -> MainChain<u32> {
+        let mut chain = MainChain::new(0);
+        for hash in 1..=length {
+            chain.push(hash);
         }
-    } else {
-        Ok(vec![])
+        chain
     }
-}
 
-fn handle_get_blocks(
-    blockchain: &BlockchainR,
-    from: BlockHash,
-    to: BlockHash,
-    reply: &mut StreamReply<Block>)
-{
-    let blockchain = blockchain.read().unwrap();
+    #[test]
+    fn headers_follow_the_newest_checkpoint() {
+        let chain = chain(100);
+        assert_eq!(header_heights(&chain, &[0, 10, 42], &100).unwrap(), 43..101);
+        // the checkpoints after `to` are not its ancestors
+        assert_eq!(header_heights(&chain, &[10, 60], &50).unwrap(), 11..51);
+        assert_eq!(header_heights(&chain, &[50], &50).unwrap(), 51..51);
+    }
+
+    #[test]
+    fn headers_are_paged() {
+        let length = 2 * MAX_HEADERS as u32 + 10;
+        let chain = chain(length);
+        let first = header_heights(&chain, &[0], &length).unwrap();
+        assert_eq!(first, 1..MAX_HEADERS + 1);
+
+        // the next page starts after the last header received
+        let last = (first.end - 1) as u32;
+        let second = header_heights(&chain, &[last], &length).unwrap();
+        assert_eq!(second, MAX_HEADERS + 1..2 * MAX_HEADERS + 1);
 
-    for x in iter::Iter::new(&blockchain.get_storage(), from, to).unwrap() {
-        match x {
-            Err(err) => reply.send_error(Error::from_error(err)),
-            Ok((_rblk, blk)) => reply.send(blk), // FIXME: use rblk
+        let last = (second.end - 1) as u32;
+        let third = header_heights(&chain, &[last], &length).unwrap();
+        assert_eq!(third, 2 * MAX_HEADERS + 1..length as usize + 1);
+    }
+
+    #[test]
+    fn headers_to_an_unknown_block() {
+        let chain = chain(10);
+        match header_heights(&chain, &[0], &42) {
+            Err(GetBlockHeadersError::UnknownTo(42)) => {}
+            other => panic!("unexpected result {:?}", other),
         }
     }
 
-    reply.close();
+    #[test]
+    fn headers_without_common_ancestor() {
+        let chain = chain(10);
+        match header_heights(&chain, &[42, 43], &10) {
+            Err(GetBlockHeadersError::NoCommonAncestor) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match header_heights(&chain, &[], &10) {
+            Err(GetBlockHeadersError::NoCommonAncestor) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
 }
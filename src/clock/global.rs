@@ -18,6 +18,12 @@ impl GlobalTime {
     }
 }
 
+impl From<SystemTime> for GlobalTime {
+    fn from(time: SystemTime) -> Self {
+        GlobalTime(time)
+    }
+}
+
 /// This is absolute time the blockchain starts expressed in system time.
 ///
 /// This is effectively T0 for the blockchain
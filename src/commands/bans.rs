@@ -0,0 +1,53 @@
+use std::{io, net::IpAddr, path::PathBuf, time::{SystemTime, UNIX_EPOCH}};
+
+use reputation::{read_bans, write_bans};
+
+#[derive(StructOpt, Debug)]
+pub enum Bans {
+    /// list the peers currently banned
+    #[structopt(name = "list")]
+    List {
+        /// the file the node saves the bans in
+        #[structopt(long = "file", parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// lift the ban of the given peer, or of all the peers if no
+    /// address is given. A running node reloads the bans shortly after.
+    #[structopt(name = "clear")]
+    Clear {
+        /// the file the node saves the bans in
+        #[structopt(long = "file", parse(from_os_str))]
+        file: PathBuf,
+
+        /// the IP address of the peer
+        #[structopt(parse(try_from_str))]
+        address: Option<IpAddr>,
+    },
+}
+
+impl Bans {
+    pub fn exec(self) -> io::Result<()> {
+        match self {
+            Bans::List { file } => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                for ban in read_bans(&file)?.into_iter().filter(|ban| ban.until > now) {
+                    println!("{}\t{}s remaining\t{}", ban.address, ban.until - now, ban.reason);
+                }
+                Ok(())
+            }
+            Bans::Clear { file, address } => {
+                let bans = match address {
+                    None => Vec::new(),
+                    Some(address) => read_bans(&file)?
+                        .into_iter()
+                        .filter(|ban| ban.address != address)
+                        .collect(),
+                };
+                write_bans(&file, &bans)
+            }
+        }
+    }
+}
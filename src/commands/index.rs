@@ -0,0 +1,45 @@
+use std::{io, path::PathBuf};
+
+use blockcfg::BlockHash;
+use blockchain::LOCAL_BLOCKCHAIN_TIP_TAG;
+use index;
+use xblockchain_storage::{tag, Storage, StorageConfig};
+
+#[derive(StructOpt, Debug)]
+pub enum Index {
+    /// build the index again from the blocks of the storage, up to the
+    /// tip of the node. The node must not be running.
+    #[structopt(name = "rebuild")]
+    Rebuild {
+        /// the storage of the node
+        #[structopt(long = "storage", parse(from_os_str), default_value = "pool-storage")]
+        storage: PathBuf,
+
+        /// the file of the index
+        #[structopt(long = "file", parse(from_os_str))]
+        file: PathBuf,
+
+        /// the hash of the genesis block (block0) of the blockchain,
+        /// where the chain of the tip ends
+        #[structopt(long = "block0-hash", parse(try_from_str))]
+        block0_hash: BlockHash,
+    },
+}
+
+impl Index {
+    pub fn exec(self) -> io::Result<()> {
+        match self {
+            Index::Rebuild { storage, file, block0_hash } => {
+                let storage = Storage::init(&StorageConfig::new(&storage))
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("{:?}", err)))?;
+                let tip = tag::read_hash(&storage, &LOCAL_BLOCKCHAIN_TIP_TAG)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the storage has no tip"))?;
+                let mut index = index::Index::new(file);
+                index.update(&storage, &block0_hash, &tip)?;
+                index.save()?;
+                println!("index rebuilt up to {}", tip);
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use std::{io, path::PathBuf};
+
+use secrets::NodeSecret;
+
+#[derive(StructOpt, Debug)]
+pub enum Keygen {
+    /// generate a new secret key for a leader node. The public key is
+    /// printed as an entry of the `bft.leaders` of the configuration.
+    #[structopt(name = "generate")]
+    Generate {
+        /// the file to write the secret key to, to give to the node with
+        /// `--secret`. An existing file is not overwritten.
+        #[structopt(long = "secret", parse(from_os_str))]
+        secret: PathBuf,
+    },
+    /// print the public key of an existing secret key as an entry of
+    /// the `bft.leaders` of the configuration
+    #[structopt(name = "public")]
+    Public {
+        /// the file of the secret key
+        #[structopt(long = "secret", parse(from_os_str))]
+        secret: PathBuf,
+    },
+}
+
+impl Keygen {
+    pub fn exec(self) -> io::Result<()> {
+        let secret = match self {
+            Keygen::Generate { secret } => NodeSecret::generate(&secret)?,
+            Keygen::Public { secret } => NodeSecret::load(&secret)?,
+        };
+        // the indentation of the leaders list of demo-config.yaml, the
+        // output can be appended to it
+        println!("    - {}", secret.public());
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+//! operator commands
+//!
+//! These commands are given as the first argument of the node
+//! (e.g. `xchain bans list --file bans.yaml`). They perform a one off
+//! task and exit instead of starting the node.
+//!
+
+pub mod bans;
+pub mod index;
+pub mod keygen;
+
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+        name = "xchain",
+        raw(setting = "structopt::clap::AppSettings::ColoredHelp"),
+    )
+]
+pub enum Command {
+    /// list or clear the banned peers
+    #[structopt(name = "bans")]
+    Bans(bans::Bans),
+    /// maintain the address and transaction index
+    #[structopt(name = "index")]
+    Index(index::Index),
+    /// generate the secret key of a leader, or print its public key
+    #[structopt(name = "keygen")]
+    Keygen(keygen::Keygen),
+}
+
+const COMMANDS: &'static [&'static str] = &["bans", "index", "keygen"];
+
+impl Command {
+    /// check if the node was started with one of the operator commands
+    pub fn is_command() -> bool {
+        std::env::args()
+            .nth(1)
+            .map(|arg| COMMANDS.contains(&arg.as_str()))
+            .unwrap_or(false)
+    }
+
+    /// run the command, returns the exit code of the process
+    pub fn exec(self) -> i32 {
+        let result = match self {
+            Command::Bans(bans) => bans.exec(),
+            Command::Index(index) => index.exec(),
+            Command::Keygen(keygen) => keygen.exec(),
+        };
+        match result {
+            Ok(()) => 0,
+            Err(err) => {
+                eprintln!("{}", err);
+                1
+            }
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! address and transaction index
+//!
+//! The storage only looks blocks up by hash. The optional index maps
+//! the transaction ids to the block and position they are in, and the
+//! addresses to the transactions paying to or spending from them.
+//!
+//! The index follows the tip changes of the blockchain: on a new tip it
+//! looks for the newest block it indexed on the chain of the new tip,
+//! removes the blocks indexed above it (the blocks of the abandoned
+//! fork) and indexes the blocks of the new chain. The index is kept in
+//! memory and saved to its file periodically and on shutdown. The
+//! `index rebuild` command builds it again from the storage.
+//!
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use bincode;
+use futures::{prelude::*, sync::mpsc};
+use xblockchain::block::Block;
+use xblockchain_storage::{block_read, Storage};
+
+use blockcfg::{Address, BlockHash, Transaction, TransactionId, TxoPointer};
+use utils::task::{run_blocking, Shutdown};
+
+/// the version of the format of the index file
+const INDEX_VERSION: u32 = 1;
+
+/// how often the index is saved while following the tip
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// where a transaction is in the chain
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxLocation {
+    pub block: BlockHash,
+    /// the position of the transaction in the block
+    pub position: usize,
+}
+
+/// a transaction paying to or spending from an address
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressEntry {
+    pub transaction: TransactionId,
+    pub block: BlockHash,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Index {
+    /// the indexed chain, from the first block to the tip
+    chain: Vec<BlockHash>,
+    /// the position of the blocks in `chain`
+    heights: BTreeMap<BlockHash, usize>,
+    transactions: BTreeMap<TransactionId, TxLocation>,
+    /// the address of every output, to find the addresses the inputs
+    /// spend from
+    outputs: BTreeMap<TxoPointer, String>,
+    /// the history of the addresses (by their base58 form), in the
+    /// order of the chain
+    addresses: BTreeMap<String, Vec<AddressEntry>>,
+
+    /// where the index is saved
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    #[serde(skip)]
+    saved: Option<Instant>,
+}
+
+pub type IndexR = Arc<RwLock<Index>>;
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn ::std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn block_transactions(block: &Block) -> Vec<&Transaction> {
+    match block {
+        Block::BoundaryBlock(_) => Vec::new(),
+        Block::MainBlock(blk) => blk.body.tx.iter().collect(),
+    }
+}
+
+impl Index {
+    /// an empty index, saved to the given file
+    pub fn new(path: PathBuf) -> Self {
+        Index {
+            path: Some(path),
+            ..Index::default()
+        }
+    }
+
+    /// read the index from the given file, an absent file gives an
+    /// empty index.
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Index::new(path)),
+            Err(err) => return Err(err),
+        };
+        let version: u32 = bincode::deserialize(&bytes).map_err(invalid_data)?;
+        if version != INDEX_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported index version {} (expected {}), use `xchain index rebuild`",
+                version, INDEX_VERSION
+            )));
+        }
+        let body = &bytes[bincode::serialized_size(&version).map_err(invalid_data)? as usize..];
+        let mut index: Index = bincode::deserialize(body).map_err(invalid_data)?;
+        index.path = Some(path);
+        Ok(index)
+    }
+
+    pub fn save(&mut self) -> io::Result<()> {
+        let path = match self.path {
+            None => return Ok(()),
+            Some(ref path) => path.clone(),
+        };
+        let mut bytes = bincode::serialize(&INDEX_VERSION).map_err(invalid_data)?;
+        bytes.extend(bincode::serialize(self).map_err(invalid_data)?);
+        write_file(&path, &bytes)?;
+        self.saved = Some(Instant::now());
+        Ok(())
+    }
+
+    fn save_periodically(&mut self) {
+        let due = self.saved.map(|saved| saved.elapsed() > SAVE_INTERVAL).unwrap_or(true);
+        if due {
+            if let Err(err) = self.save() {
+                warn!("cannot save the index: {}", err);
+            }
+        }
+    }
+
+    pub fn tip(&self) -> Option<&BlockHash> {
+        self.chain.last()
+    }
+
+    pub fn transaction(&self, id: &TransactionId) -> Option<&TxLocation> {
+        self.transactions.get(id)
+    }
+
+    /// the transactions paying to or spending from the address, the
+    /// oldest first
+    pub fn address_history(&self, address: &Address) -> &[AddressEntry] {
+        self.addresses
+            .get(&format!("{}", address))
+            .map(|entries| entries.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// index the chain ending at `tip`, removing the blocks indexed on
+    /// another fork. The index is unchanged on error.
+    pub fn update(&mut self, storage: &Storage, genesis: &BlockHash, tip: &BlockHash) -> io::Result<()> {
+        // walk back from the tip to the newest block already indexed,
+        // the walk ends at the genesis (which is not in the storage)
+        let mut blocks = Vec::new();
+        let mut current = tip.clone();
+        let fork = loop {
+            if let Some(height) = self.heights.get(&current) {
+                break Some(*height);
+            }
+            match block_read(storage, &current) {
+                None if current == *genesis => break None,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("the block {} of the chain of {} is not in the storage", current, tip),
+                    ))
+                }
+                Some(rblk) => {
+                    let block = rblk.decode().map_err(|err| invalid_data(format!("{:?}", err)))?;
+                    let parent = block.get_header().get_previous_header();
+                    blocks.push((current, block));
+                    current = parent;
+                }
+            }
+        };
+
+        let keep = fork.map(|height| height + 1).unwrap_or(0);
+        if self.chain.len() > keep {
+            info!("index: rolling back {} blocks", self.chain.len() - keep);
+        }
+        while self.chain.len() > keep {
+            let hash = self.chain.pop().unwrap();
+            self.remove_block(storage, &hash)?;
+        }
+        for (hash, block) in blocks.into_iter().rev() {
+            self.add_block(hash, &block);
+        }
+        Ok(())
+    }
+
+    /// the addresses a transaction pays to or spends from, the spent
+    /// outputs must be in the index
+    fn touched_addresses(&self, tx: &Transaction) -> BTreeSet<String> {
+        let inputs = tx
+            .tx
+            .inputs
+            .iter()
+            .filter_map(|input| self.outputs.get(input).cloned());
+        let outputs = tx.tx.outputs.iter().map(|output| format!("{}", output.address));
+        inputs.chain(outputs).collect()
+    }
+
+    fn add_block(&mut self, hash: BlockHash, block: &Block) {
+        for (position, tx) in block_transactions(block).into_iter().enumerate() {
+            let id = tx.tx.id();
+            for (index, output) in tx.tx.outputs.iter().enumerate() {
+                let txo = TxoPointer::new(id.clone(), index as u32);
+                self.outputs.insert(txo, format!("{}", output.address));
+            }
+            for address in self.touched_addresses(tx) {
+                self.addresses.entry(address).or_insert(Vec::new()).push(AddressEntry {
+                    transaction: id.clone(),
+                    block: hash.clone(),
+                });
+            }
+            self.transactions.insert(id, TxLocation { block: hash.clone(), position: position });
+        }
+        self.heights.insert(hash.clone(), self.chain.len());
+        self.chain.push(hash);
+    }
+
+    fn remove_block(&mut self, storage: &Storage, hash: &BlockHash) -> io::Result<()> {
+        // the blocks of the abandoned forks stay in the storage
+        let block = block_read(storage, hash)
+            .ok_or_else(|| invalid_data(format!("indexed block {} is not in the storage", hash)))?
+            .decode()
+            .map_err(|err| invalid_data(format!("{:?}", err)))?;
+        for tx in block_transactions(&block).into_iter().rev() {
+            let id = tx.tx.id();
+            for address in self.touched_addresses(tx) {
+                let empty = match self.addresses.get_mut(&address) {
+                    None => false,
+                    Some(entries) => {
+                        entries.retain(|entry| entry.transaction != id);
+                        entries.is_empty()
+                    }
+                };
+                if empty {
+                    self.addresses.remove(&address);
+                }
+            }
+            for index in 0..tx.tx.outputs.len() {
+                self.outputs.remove(&TxoPointer::new(id.clone(), index as u32));
+            }
+            self.transactions.remove(&id);
+        }
+        self.heights.remove(hash);
+        Ok(())
+    }
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(tmp, path)
+}
+
+/// follow the tip changes of the blockchain until the shutdown, the
+/// index is saved when the task stops.
+pub fn run(
+    index: IndexR,
+    storage: Storage,
+    genesis: BlockHash,
+    tips: mpsc::UnboundedReceiver<BlockHash>,
+    shutdown: Shutdown,
+) -> impl Future<Item = (), Error = ()> {
+    let storage = Arc::new(storage);
+    let update_index = index.clone();
+    tips.for_each(move |tip| {
+        let index = update_index.clone();
+        let storage = storage.clone();
+        let genesis = genesis.clone();
+        run_blocking(move || {
+            let mut index = index.write().unwrap();
+            match index.update(&storage, &genesis, &tip) {
+                Ok(()) => index.save_periodically(),
+                Err(err) => error!("cannot index the chain up to {}: {}", tip, err),
+            }
+        })
+    })
+    .select(shutdown.wait())
+    .then(move |_| {
+        if let Err(err) = index.write().unwrap().save() {
+            warn!("cannot save the index: {}", err);
+        }
+        Ok(())
+    })
+}
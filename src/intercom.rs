@@ -1,35 +1,154 @@
-use blockcfg::{Block, Header, BlockHash, Transaction};
+use blockcfg::{Address, Block, Header, BlockHash, RawBlock, Transaction, TransactionId, TxOut, TxoPointer};
+use index::{AddressEntry, TxLocation};
+use tx_status::TxStatus;
+use network::PeerId;
+use settings::network::Connection;
 
+use futures::{
+    prelude::*,
+    sync::{mpsc, oneshot},
+};
 use std::fmt::{self, Debug, Display};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// The kind of an intercom error, so the receiver can tell the
+/// failures apart when answering a peer.
+///
+/// The kinds are serialised with their `as_str` name, which is part
+/// of the log format: don't rename them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// the requested block or header does not exist
+    NotFound,
+    /// the stored data cannot be read or decoded
+    Corrupt,
+    /// the requested range is not a part of our chain
+    InvalidRange,
+    /// the node is serving too many requests
+    Busy,
+    /// the request needs more than the node gives to one request
+    /// (e.g. too many results)
+    ResourceExhausted,
+    /// the peer did not answer in time
+    DeadlineExceeded,
+    /// the peer is disconnected or closed the request
+    Unavailable,
+    /// the signature of the transaction is invalid
+    InvalidSignature,
+    /// the transaction spends an output already spent
+    DoubleSpend,
+    /// the transaction is already recorded
+    AlreadyExists,
+    /// the request needs a feature not enabled on this node
+    Unsupported,
+    /// any other failure
+    Internal,
+}
+
+impl ErrorKind {
+    /// the stable name of the kind, used in the logs
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::Corrupt => "corrupt",
+            ErrorKind::InvalidRange => "invalid_range",
+            ErrorKind::Busy => "busy",
+            ErrorKind::ResourceExhausted => "resource_exhausted",
+            ErrorKind::DeadlineExceeded => "deadline_exceeded",
+            ErrorKind::Unavailable => "unavailable",
+            ErrorKind::InvalidSignature => "invalid_signature",
+            ErrorKind::DoubleSpend => "double_spend",
+            ErrorKind::AlreadyExists => "already_exists",
+            ErrorKind::Unsupported => "unsupported",
+            ErrorKind::Internal => "internal",
+        }
+    }
+
+    /// the gRPC status code to answer the request with
+    pub fn grpc_code(self) -> i32 {
+        match self {
+            ErrorKind::NotFound => 5,          // NOT_FOUND
+            ErrorKind::Corrupt => 15,          // DATA_LOSS
+            ErrorKind::InvalidRange => 11,     // OUT_OF_RANGE
+            ErrorKind::Busy => 8,              // RESOURCE_EXHAUSTED
+            ErrorKind::ResourceExhausted => 8, // RESOURCE_EXHAUSTED
+            ErrorKind::DeadlineExceeded => 4,  // DEADLINE_EXCEEDED
+            ErrorKind::Unavailable => 14,      // UNAVAILABLE
+            ErrorKind::InvalidSignature => 3,  // INVALID_ARGUMENT
+            ErrorKind::DoubleSpend => 9,       // FAILED_PRECONDITION
+            ErrorKind::AlreadyExists => 6,     // ALREADY_EXISTS
+            ErrorKind::Unsupported => 12,      // UNIMPLEMENTED
+            ErrorKind::Internal => 13,         // INTERNAL
+        }
+    }
+
+    /// the `RecordTransactionResponse.Result` value (see
+    /// `proto/node.proto`) to answer a rejected transaction with
+    pub fn record_transaction_result(self) -> i32 {
+        match self {
+            ErrorKind::InvalidSignature => 2,  // INVALID_SIGNATURE
+            ErrorKind::DoubleSpend => 3,       // DOUBLE_SPEND
+            ErrorKind::AlreadyExists => 4,     // ALREADY_EXISTS
+            _ => 1,                            // UNKNOWN_ERROR
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
 
 /// The error values passed via intercom messages.
 #[derive(Debug)]
-pub struct Error(Box<dyn std::error::Error + Send + Sync>);
+pub struct Error {
+    kind: ErrorKind,
+    cause: Box<dyn std::error::Error + Send + Sync>,
+}
 
 impl Error {
+    pub fn new<E>(kind: ErrorKind, error: E) -> Self
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>>
+    {
+        Error {
+            kind: kind,
+            cause: error.into(),
+        }
+    }
+
     pub fn from_error<E>(error: E) -> Self
     where
         E: std::error::Error + Send + Sync + 'static
     {
-        Error(error.into())
+        Error::new(ErrorKind::Internal, error)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
     }
 }
 
 impl From<String> for Error {
     fn from(s: String) -> Error {
-        Error(s.into())
+        Error::new(ErrorKind::Internal, s)
     }
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        Display::fmt(&self.0, f)
+        Display::fmt(&self.cause, f)
     }
 }
 
 impl std::error::Error for Error {
     fn cause(&self) -> Option<&std::error::Error> {
-        self.0.cause()
+        self.cause.cause()
     }
 }
 
@@ -49,19 +168,290 @@ pub trait StreamReply<T>: Debug {
     fn send(&mut self, item: T);
     fn send_error(&mut self, error: Error);
     fn close(&mut self);
+
+    /// the receiver is gone (e.g. the peer disconnected), the producer
+    /// should stop sending items.
+    fn is_cancelled(&self) -> bool {
+        false
+    }
 }
 
 pub type BoxReply<T> = Box<dyn Reply<T> + Send>;
 pub type BoxStreamReply<T> = Box<dyn StreamReply<T> + Send>;
 
-// TODO
+/// stream of blocks sent as they are stored, the network wraps the
+/// bytes in the `Block` message without decoding and encoding them
+/// again (see `network::query::block_response`).
+pub type BoxRawStreamReply = BoxStreamReply<RawBlock>;
+
+fn reply_dropped() -> Error {
+    Error::new(ErrorKind::Internal, "the request was dropped without a reply")
+}
+
+/// create a reply handle to give with a request, and the future
+/// resolving to the reply.
+pub fn reply_channel<T>() -> (ReplyHandle<T>, ReplyFuture<T>) {
+    let (sender, receiver) = oneshot::channel();
+    (ReplyHandle(Some(sender)), ReplyFuture(receiver))
+}
+
+/// `Reply` implementation sending the reply to a `ReplyFuture`
+#[derive(Debug)]
+pub struct ReplyHandle<T>(Option<oneshot::Sender<Result<T, Error>>>);
+
+impl<T: Debug> Reply<T> for ReplyHandle<T> {
+    fn reply_ok(&mut self, item: T) {
+        self.reply(Ok(item))
+    }
+    fn reply_error(&mut self, error: Error) {
+        self.reply(Err(error))
+    }
+    fn reply(&mut self, result: Result<T, Error>) {
+        match self.0.take() {
+            // the receiver may not be interested anymore
+            Some(sender) => { let _ = sender.send(result); }
+            None => warn!("request replied more than once"),
+        }
+    }
+}
+
+/// the reply of a request, fails if the handle is dropped without
+/// replying.
+#[derive(Debug)]
+pub struct ReplyFuture<T>(oneshot::Receiver<Result<T, Error>>);
+
+impl<T> Future for ReplyFuture<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<T, Error> {
+        match self.0.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(Ok(item))) => Ok(Async::Ready(item)),
+            Ok(Async::Ready(Err(error))) => Err(error),
+            Err(oneshot::Canceled) => Err(reply_dropped()),
+        }
+    }
+}
+
+/// create a stream reply to give with a request, and the stream of
+/// the replied items. At most `buffer` items are queued: the producer
+/// never waits, a consumer falling further behind receives a
+/// `ResourceExhausted` error as the last item of the stream.
+pub fn stream_reply_channel<T>(buffer: usize) -> (ReplyStream<T>, ReplyStreamReceiver<T>) {
+    let (sender, receiver) = mpsc::channel(buffer);
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let stream = ReplyStream {
+        // each sender has a slot of its own in the channel, the one of
+        // the overflow sender is kept for the final error
+        overflow: Some(sender.clone()),
+        sender: Some(sender),
+        cancelled: cancelled.clone(),
+    };
+    let receiver = ReplyStreamReceiver {
+        receiver: receiver,
+        cancelled: cancelled,
+    };
+    (stream, receiver)
+}
+
+/// `StreamReply` implementation sending the items to a
+/// `ReplyStreamReceiver`
+#[derive(Debug)]
+pub struct ReplyStream<T> {
+    sender: Option<mpsc::Sender<Result<T, Error>>>,
+    overflow: Option<mpsc::Sender<Result<T, Error>>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl<T> ReplyStream<T> {
+    fn send_item(&mut self, item: Result<T, Error>) {
+        if self.is_closed() {
+            return;
+        }
+        let sent = match self.sender {
+            None => return,
+            Some(ref mut sender) => sender.try_send(item),
+        };
+        match sent {
+            Ok(()) => {}
+            Err(ref err) if err.is_full() => {
+                warn!("the receiver of a stream reply is too slow, ending the stream");
+                self.sender = None;
+                if let Some(mut overflow) = self.overflow.take() {
+                    let error = Error::new(ErrorKind::ResourceExhausted, "the receiver fell too far behind the stream");
+                    let _ = overflow.try_send(Err(error));
+                }
+            }
+            // the receiver is gone
+            Err(_) => self.close_senders(),
+        }
+    }
+
+    fn close_senders(&mut self) {
+        self.sender = None;
+        self.overflow = None;
+    }
+
+    /// no more items can be sent, the stream ended or the receiver is
+    /// gone
+    fn is_closed(&self) -> bool {
+        self.sender.is_none() || self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl<T: Debug> StreamReply<T> for ReplyStream<T> {
+    fn send(&mut self, item: T) {
+        self.send_item(Ok(item))
+    }
+    fn send_error(&mut self, error: Error) {
+        self.send_item(Err(error))
+    }
+    fn close(&mut self) {
+        self.close_senders()
+    }
+    fn is_cancelled(&self) -> bool {
+        self.is_closed()
+    }
+}
 
-pub type TransactionMsg = u32;
+/// the items replied to a request, the stream ends when the producer
+/// closes the reply. Dropping the receiver cancels the reply.
+#[derive(Debug)]
+pub struct ReplyStreamReceiver<T> {
+    receiver: mpsc::Receiver<Result<T, Error>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl<T> ReplyStreamReceiver<T> {
+    /// tell the producer to stop sending items
+    pub fn cancel(&mut self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.receiver.close();
+    }
+}
+
+impl<T> Stream for ReplyStreamReceiver<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<T>, Error> {
+        match self.receiver.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::Ready(Some(Ok(item)))) => Ok(Async::Ready(Some(item))),
+            Ok(Async::Ready(Some(Err(error)))) => Err(error),
+            Err(()) => Err(reply_dropped()),
+        }
+    }
+}
+
+impl<T> Drop for ReplyStreamReceiver<T> {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+/// The status of a transaction proposed by a peer, the values are the
+/// ones of `ProposeTransactionsResponse.Status` in `proto/node.proto`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProposeStatus {
+    /// the transaction is new to us, the peer sends it
+    New = 0,
+    /// the transaction is already in our pool
+    AlreadyExists = 1,
+}
+
+/// Messages for the transaction task
+#[derive(Debug)]
+pub enum TransactionMsg {
+    /// the transactions announced by a peer, the reply gives the
+    /// status of each of them in the same order
+    ProposeTransactions(Vec<TransactionId>, BoxReply<Vec<ProposeStatus>>),
+    /// the transactions of our pool with the given ids, the ones not
+    /// in the pool are not in the reply
+    GetTransactions(Vec<TransactionId>, BoxReply<Vec<Transaction>>),
+    /// new transactions, sent by the given peer
+    SendTransactions(Vec<Transaction>, Option<PeerId>),
+}
 
 /// Client messages, mainly requests from connected peers to our node.
 /// Fetching the block headers, the block, the tip
 #[derive(Debug)]
 pub enum ClientMsg {
+    GetBlockTip(BoxReply<Header>),
+    /// the headers after the newest of the checkpoints that is an
+    /// ancestor of the given block, up to this block. Long ranges are
+    /// sent in several replies: ask again with the last header received
+    /// as checkpoint.
+    GetBlockHeaders(Vec<BlockHash>, BlockHash, BoxReply<Vec<Header>>),
+    GetBlocks(BlockHash, BlockHash, BoxRawStreamReply),
+    /// the unspent outputs at our current tip
+    GetUtxos(UtxoQuery, BoxReply<Utxos>),
+    /// where the transaction is in our chain, needs the index
+    GetTransaction(TransactionId, BoxReply<TxLocation>),
+    /// the transactions paying to or spending from the address, the
+    /// oldest first, needs the index
+    GetAddressHistory(Address, BoxReply<Vec<AddressEntry>>),
+    /// the status of a transaction of the pool
+    GetTransactionStatus(TransactionId, BoxReply<TxStatus>),
+    /// the status changes of the given transactions, of all the
+    /// transactions if the list is empty
+    SubscribeTransactionStatus(Vec<TransactionId>, BoxStreamReply<(TransactionId, TxStatus)>),
+}
+
+impl ClientMsg {
+    /// answer the query with the error, e.g. when it cannot be served
+    pub fn reply_error(self, error: Error) {
+        match self {
+            ClientMsg::GetBlockTip(mut reply) => reply.reply_error(error),
+            ClientMsg::GetBlockHeaders(_, _, mut reply) => reply.reply_error(error),
+            ClientMsg::GetBlocks(_, _, mut reply) => {
+                reply.send_error(error);
+                reply.close();
+            }
+            ClientMsg::GetUtxos(_, mut reply) => reply.reply_error(error),
+            ClientMsg::GetTransaction(_, mut reply) => reply.reply_error(error),
+            ClientMsg::GetAddressHistory(_, mut reply) => reply.reply_error(error),
+            ClientMsg::GetTransactionStatus(_, mut reply) => reply.reply_error(error),
+            ClientMsg::SubscribeTransactionStatus(_, mut reply) => {
+                reply.send_error(error);
+                reply.close();
+            }
+        }
+    }
+}
+
+/// the unspent outputs looked up by `ClientMsg::GetUtxos`
+#[derive(Debug, Clone)]
+pub enum UtxoQuery {
+    /// the outputs paid to any of the addresses
+    Addresses(Vec<Address>),
+    /// the outputs with the given references, the spent or unknown
+    /// references are not in the reply
+    OutputRefs(Vec<TxoPointer>),
+}
+
+/// unspent outputs, as of the given tip
+#[derive(Debug, Clone)]
+pub struct Utxos {
+    pub tip: BlockHash,
+    pub outputs: Vec<(TxoPointer, TxOut)>,
+}
+
+/// A client message and the peer it comes from, the client task limits
+/// the resources each peer can use.
+#[derive(Debug)]
+pub struct ClientQuery {
+    pub peer: PeerId,
+    pub msg: ClientMsg,
+}
+
+/// Requests from our node to one of the connected peers. The replies
+/// are given back to the handles once the peer answers, or with an
+/// error if the peer does not answer in time.
+#[derive(Debug)]
+pub enum PeerMsg {
     GetBlockTip(BoxReply<Header>),
     GetBlockHeaders(Vec<BlockHash>, BlockHash, BoxReply<Vec<Header>>),
     GetBlocks(BlockHash, BlockHash, BoxStreamReply<Block>),
@@ -70,12 +460,23 @@ pub enum ClientMsg {
 /// General Block Message for the block task
 #[derive(Debug, Clone)]
 pub enum BlockMsg {
-    /// A untrusted Block has been received from the network task
-    NetworkBlock(Block),
+    /// A untrusted Block has been received from the network task,
+    /// from the given peer
+    NetworkBlock(PeerId, Connection, Block),
     /// A trusted Block has been received from the leadership task
     LeadershipBlock(Block),
 }
 
+/// the missing ancestors of a block received from a peer, fetched by
+/// the sync task from that peer
+#[derive(Debug, Clone)]
+pub struct SyncMsg {
+    pub peer: PeerId,
+    pub connection: Connection,
+    /// the parent of the block, not in our chain
+    pub parent: BlockHash,
+}
+
 /// Message to broadcast to all the connected peers (that requested to subscribe
 /// to our blockchain).
 ///
@@ -3,6 +3,7 @@ extern crate clap;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_yaml;
+extern crate bincode;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
@@ -13,8 +14,13 @@ extern crate xblockchain;
 extern crate xblockchain_storage;
 extern crate exe_common;
 extern crate protocol_tokio as protocol;
+extern crate cbor_event;
 extern crate futures;
 extern crate tokio;
+extern crate tokio_signal;
+extern crate tokio_threadpool;
+extern crate clear_on_drop;
+extern crate rand;
 
 pub mod clock;
 pub mod blockchain;
@@ -25,73 +31,200 @@ pub mod utils;
 pub mod intercom;
 pub mod settings;
 pub mod blockcfg;
+pub mod reputation;
+pub mod commands;
+pub mod bootstrap;
+pub mod client;
+pub mod index;
+pub mod tx_status;
+pub mod sync;
+pub mod block_template;
+pub mod secrets;
 
 use std::path::{PathBuf};
 
 use settings::Settings;
-use state::State;
+use state::{ProtocolParameters, Snapshots};
 use transaction::{TPool};
 use blockchain::{Blockchain, BlockchainR};
-use utils::task::{Tasks, Task, TaskMessageBox};
-use intercom::{BlockMsg, ClientMsg, TransactionMsg};
+use utils::task::{run_blocking, RestartPolicy, Shutdown, TaskMessageBox, Tasks};
+use intercom::{BlockMsg, ProposeStatus, SyncMsg, TransactionMsg};
+use reputation::{Offence, Reputation, ReputationR};
+use commands::Command;
+use bootstrap::{Bootstrap, SyncStatus};
+use sync::Synchroniser;
+use secrets::Leader;
+use structopt::StructOpt;
 
 use blockcfg::*;
 
-use std::sync::{Arc, RwLock, mpsc::Receiver};
-use std::{time, thread};
+use std::sync::{Arc, RwLock};
 
+use std::time::{Duration, Instant};
+
+use futures::{future::{self, Either}, stream, Future, Stream};
+use tokio::timer::Delay;
+use xblockchain::address::StakeholderId;
+use xblockchain::block::EpochSlotId;
 use xblockchain_storage::StorageConfig;
 
 pub type TODO = u32;
 pub type TPoolR = Arc<RwLock<TPool<TransactionId, Transaction>>>;
 
-fn transaction_task(_tpool: TPoolR, r: Receiver<TransactionMsg>) {
-    loop {
-        let tquery = r.recv().unwrap();
-        println!("transaction received: {}", tquery)
+/// the tasks processing messages are restarted if they panic on a
+/// message
+const TASK_RESTART_POLICY: RestartPolicy = RestartPolicy::OnPanic { max_restarts: 10 };
+
+/// maximum number of blocks waiting to be processed, the network stops
+/// reading blocks from the peers beyond this
+const BLOCK_TASK_CAPACITY: usize = 1024;
+
+/// how often the leadership checks if the chain started
+const SLOT_POLL: Duration = Duration::from_secs(1);
+
+/// maximum number of unconnected blocks waiting for their ancestors to
+/// be fetched, the others are dropped until the queue drains
+const SYNC_TASK_CAPACITY: usize = 64;
+
+fn transaction_task(tpool: &TPoolR, blockchain: &BlockchainR, reputation: &ReputationR, peers: &network::ConnectedPeers, relay: &network::TransactionRelay, tquery: TransactionMsg) {
+    match tquery {
+        TransactionMsg::ProposeTransactions(ids, mut reply) => {
+            let tpool = tpool.read().unwrap();
+            let statuses = ids.iter().map(|id| {
+                if tpool.exist(id) { ProposeStatus::AlreadyExists } else { ProposeStatus::New }
+            }).collect();
+            reply.reply_ok(statuses)
+        }
+        TransactionMsg::GetTransactions(ids, mut reply) => {
+            let tpool = tpool.read().unwrap();
+            let transactions = ids.iter()
+                .filter_map(|id| tpool.content.get(id).map(|(_, tx)| tx.clone()))
+                .collect();
+            reply.reply_ok(transactions)
+        }
+        TransactionMsg::SendTransactions(transactions, from) => {
+            let blockchain = blockchain.read().unwrap();
+            let parameters = ProtocolParameters::from_genesis(blockchain.get_genesis_data());
+            let utxos = blockchain.get_utxos();
+            let mut tpool = tpool.write().unwrap();
+            let mut invalid = 0;
+            for tx in transactions {
+                let id = tx.tx.id();
+                if tpool.exist(&id) {
+                    continue;
+                }
+                // the inputs are unspent outputs of our tip, or outputs
+                // of the transactions of the pool
+                let verified = {
+                    let spent = tx.tx.inputs.iter()
+                        .map(|input| utxos.get(input).or_else(|| {
+                            tpool.content.get(&input.id)
+                                .and_then(|(_, parent)| parent.tx.outputs.get(input.index as usize))
+                        }))
+                        .collect::<Option<Vec<_>>>();
+                    match spent {
+                        None => Err("an input is not an unspent output".to_string()),
+                        Some(spent) => block_template::verify_transaction(&tx, &spent, &parameters),
+                    }
+                };
+                if let Err(err) = verified {
+                    debug!("transaction {} refused: {}", id, err);
+                    invalid += 1;
+                    continue;
+                }
+                tpool.add(id.clone(), tx);
+                relay.announce(&id, from);
+            }
+            if invalid > 0 {
+                if let Some(handle) = from.and_then(|peer| peers.get(peer)) {
+                    warn!("{} invalid transactions received from {}", invalid, handle.connection);
+                    reputation.write().unwrap().report(&handle.connection, Offence::InvalidTransaction);
+                }
+            }
+        }
     }
 }
 
-fn block_task(blockchain: BlockchainR, clock: clock::Clock, r: Receiver<BlockMsg>) {
-    loop {
-        let bquery = r.recv().unwrap();
-        blockchain::process(&blockchain, bquery);
-    }
+fn block_task(blockchain: &BlockchainR, reputation: &ReputationR, sync_box: &TaskMessageBox<SyncMsg>, _clock: &clock::Clock, bquery: BlockMsg) {
+    blockchain::process(blockchain, reputation, sync_box, bquery);
 }
 
-fn client_task(_blockchain: BlockchainR, r: Receiver<ClientMsg>) {
-    loop {
-        let query = r.recv().unwrap();
-        println!("client query received: {}", query)
+fn sync_task(synchroniser: &Synchroniser, reputation: &ReputationR, peers: &network::ConnectedPeers, msg: SyncMsg) {
+    let handle = match peers.get(msg.peer) {
+        None => return,
+        Some(handle) => handle,
+    };
+    if let Err(err) = synchroniser.sync(&handle, vec![handle.clone()], &msg.parent) {
+        debug!("cannot get the ancestors of {} from {}: {}", msg.parent, msg.connection, err);
+        reputation.write().unwrap().report(&msg.connection, Offence::UnconnectedBlock);
     }
 }
 
-fn leadership_task(tpool: TPoolR, clock: clock::Clock) {
-    // FIXME this is handled in thread, but the event will come from the clock on new slot event
-    //let sleep_time = time::Duration::from_secs(20);
-    loop {
-        //println!("sleeping for {:?}", sleep_time);
-        let d = clock.wait_next_slot();
-        let (epoch, idx, next_time) = clock.current_slot().unwrap();
-        println!("slept for {:?} epoch {} slot {} next_slot {:?}", d, epoch.0, idx, next_time);
-        let len = {
-            let t = tpool.read().unwrap();
-            (*t).content.len()
-        };
-        println!("leadership thread waking up (tpool = {} transactions)", len);
+/// wait for each slot and lead it, the slots are timed by the clock
+fn leadership_task(leader: Option<Leader>, tpool: TPoolR, blockchain: BlockchainR, block_box: TaskMessageBox<BlockMsg>, clock: clock::Clock, sync_status: SyncStatus, sync_distance: usize, max_block_size: usize, shutdown: Shutdown) -> impl Future<Item = (), Error = ()> {
+    let leader = Arc::new(leader);
+    stream::repeat::<(), ()>(())
+        .for_each(move |()| {
+            // before the start of the chain there is no slot yet, check
+            // again a bit later
+            let wait = clock.current_slot().map(|(_, _, next_time)| next_time).unwrap_or(SLOT_POLL);
+            let leader = Arc::clone(&leader);
+            let tpool = Arc::clone(&tpool);
+            let blockchain = Arc::clone(&blockchain);
+            let block_box = block_box.clone();
+            let clock = clock.clone();
+            let sync_status = sync_status.clone();
+            Delay::new(Instant::now() + wait)
+                .map_err(|err| error!("leadership timer failed: {}", err))
+                .and_then(move |()| run_blocking(move || {
+                    lead_slot(&leader, &tpool, &blockchain, &clock, &sync_status, sync_distance, max_block_size)
+                }))
+                .and_then(move |block| match block {
+                    None => Either::A(future::ok(())),
+                    // the block task adds the block to our chain, the
+                    // next slot waits for room in its mailbox
+                    Some(block) => Either::B(block_box.send(BlockMsg::LeadershipBlock(block))),
+                })
+        })
+        .select(shutdown.wait())
+        .map(|_| ())
+        .map_err(|_| ())
+}
 
-        // simulated task: take between 1 to 21 seconds.
-        {
-            let v = 1u64 + (rand::random::<u64>() % 20);
-            thread::sleep(time::Duration::from_secs(v))
-        };
+/// the block of the current slot, if we lead it
+fn lead_slot(leader: &Option<Leader>, tpool: &TPoolR, blockchain: &BlockchainR, clock: &clock::Clock, sync_status: &SyncStatus, sync_distance: usize, max_block_size: usize) -> Option<Block> {
+    let (epoch, idx, next_time) = clock.current_slot()?;
+    debug!("epoch {} slot {} next_slot {:?}", epoch.0, idx, next_time);
 
-        //   check elected
-        //   if elected
-        //     take set of transactions from pool
-        //     create a block
-        //     send it async to peers
+    // without the secret of a leader there is no slot to lead
+    let leader = leader.as_ref()?;
+
+    // don't create blocks on top of an old tip
+    if !sync_status.is_synced(sync_distance) {
+        info!("not synchronised with the network yet ({:?} blocks behind), skipping slot",
+              sync_status.distance());
+        return None;
+    }
+    let blockchain = blockchain.read().unwrap();
+    let chain_state = blockchain.get_chain_state();
+
+    // only the leader of the slot may create its block
+    let stakeholder = StakeholderId::new(leader.secret.public());
+    if chain_state.slot_leaders.get(idx as usize) != Some(&stakeholder) {
+        debug!("leader {} is not elected for slot {}, skipping slot", leader.index, idx);
+        return None;
     }
+
+    let parameters = ProtocolParameters::from_genesis(blockchain.get_genesis_data());
+    let template = {
+        let tpool = tpool.read().unwrap();
+        debug!("leading slot {} (tpool = {} transactions)", idx, tpool.content.len());
+        block_template::assemble(&tpool, chain_state, &parameters, max_block_size)
+    };
+    info!("block template: {} transactions, {} bytes, {} fees",
+          template.transactions.len(), template.size, template.fees);
+    let slot = EpochSlotId { epoch: u64::from(epoch.0), slotid: idx as u16 };
+    Some(block_template::make_block(template, chain_state, &parameters, slot, &leader.secret))
 }
 
 fn startup_info(gd: &GenesisData) {
@@ -99,6 +232,12 @@ fn startup_info(gd: &GenesisData) {
 }
 
 fn main() {
+    // operator commands (e.g. managing the banned peers), these
+    // don't start the node
+    if Command::is_command() {
+        std::process::exit(Command::from_args().exec());
+    }
+
     // # load parameters & config
     //
     // parse the command line arguments, the config files supplied
@@ -113,6 +252,24 @@ fn main() {
 
     startup_info(&genesis_data);
 
+    if let Some(ref block0_hash) = settings.block0_hash {
+        if genesis_data.genesis_prev != *block0_hash {
+            error!("the genesis configuration does not match the block0 hash {} (found {})",
+                   block0_hash, genesis_data.genesis_prev);
+            std::process::exit(1);
+        }
+    }
+
+    let leader = secrets::load_leader(&settings.secret, &settings.leaders).unwrap_or_else(|err| {
+        error!("{}", err);
+        std::process::exit(1)
+    });
+    match leader {
+        Some(ref leader) => info!("leading the slots of the leader {} ({})", leader.index, leader.secret.public()),
+        None if !settings.secret.is_empty() => warn!("no secret matches a configured leader, the node will not lead slots"),
+        None => info!("no secret given, the node will not lead slots"),
+    }
+
     let clock = {
         let initial_epoch = clock::ClockEpochConfiguration {
             slot_duration: genesis_data.slot_duration,
@@ -121,13 +278,23 @@ fn main() {
         clock::Clock::new(genesis_data.start_time, initial_epoch)
     };
 
-    let mut state = State::new();
-
     let pathbuf = PathBuf::from(r"pool-storage"); // FIXME HARDCODED should come from config
     let storage_config = StorageConfig::new(&pathbuf);
-    let blockchain_data = Blockchain::from_storage(&storage_config);
+    let snapshots = Snapshots::new(state::snapshot_dir(&pathbuf));
+    let blockchain_data = Blockchain::from_storage(genesis_data.clone(), &storage_config, snapshots);
     let blockchain = Arc::new(RwLock::new(blockchain_data));
 
+    let reputation = match settings.bans_file {
+        None => Reputation::new(reputation::DEFAULT_BAN_DURATION),
+        Some(ref path) => {
+            Reputation::load(path.clone(), reputation::DEFAULT_BAN_DURATION).unwrap_or_else(|err| {
+                error!("cannot read the banned peers from {}: {}", path.display(), err);
+                std::process::exit(1)
+            })
+        }
+    };
+    let reputation = Arc::new(RwLock::new(reputation));
+
     let mut tasks = Tasks::new();
 
     // # Bootstrap phase
@@ -136,17 +303,13 @@ fn main() {
     // to the latest network state (or close to latest). until this happen, we don't participate in the network
     // (no block creation) and our network connection(s) is only use to download data.
     //
-    // Various aspects to do, similar to hermes:
-    // * download all the existing blocks
-    // * verify all the downloaded blocks
-    // * network / peer discoveries (?)
+    // The bootstrap needs the network to be running, it is started below, see
+    // the `bootstrap` module. The leadership waits on the sync status.
+    //
+    // Still to do, similar to hermes:
     // * gclock sync ?
-
-    // Read block state
-    // init storage
-    // create blockchain storage
-
-    // ** TODO **
+    let peers = network::ConnectedPeers::new();
+    let sync_status = SyncStatus::new();
 
     // # Active phase
     //
@@ -164,20 +327,74 @@ fn main() {
     let tpool_data : TPool<TransactionId, Transaction> = TPool::new();
     let tpool = Arc::new(RwLock::new(tpool_data));
 
+    let relay = network::TransactionRelay::new();
+
     let transaction_task = {
         let tpool = Arc::clone(&tpool);
-        tasks.task_create_with_inputs("transaction", move |r| transaction_task(tpool, r))
+        let blockchain = Arc::clone(&blockchain);
+        let reputation = Arc::clone(&reputation);
+        let peers = peers.clone();
+        let relay = relay.clone();
+        tasks.task_create_with_inputs("transaction", TASK_RESTART_POLICY, move |tquery| {
+            transaction_task(&tpool, &blockchain, &reputation, &peers, &relay, tquery)
+        })
+    };
+
+    // fetches the missing ancestors of the blocks the peers send us,
+    // off the block task
+    let sync_task = {
+        let reputation = Arc::clone(&reputation);
+        let peers = peers.clone();
+        let synchroniser = Synchroniser::new(Arc::clone(&blockchain), Arc::clone(&reputation), sync_status.clone(), None);
+        tasks.task_create_with_bounded_inputs("sync", TASK_RESTART_POLICY, SYNC_TASK_CAPACITY, move |msg| {
+            sync_task(&synchroniser, &reputation, &peers, msg)
+        })
     };
 
     let block_task = {
         let blockchain = Arc::clone(&blockchain);
+        let reputation = Arc::clone(&reputation);
         let clock = clock.clone();
-        tasks.task_create_with_inputs("block", move |r| block_task(blockchain, clock, r))
+        tasks.task_create_with_bounded_inputs("block", TASK_RESTART_POLICY, BLOCK_TASK_CAPACITY, move |bquery| {
+            block_task(&blockchain, &reputation, &sync_task, &clock, bquery)
+        })
+    };
+
+    // the optional address and transaction index, following our tip
+    let index = settings.index_file.clone().map(|path| {
+        let index = index::Index::load(path.clone()).unwrap_or_else(|err| {
+            warn!("cannot load the index from {}, indexing from scratch: {}", path.display(), err);
+            index::Index::new(path)
+        });
+        let index = Arc::new(RwLock::new(index));
+        let tips = blockchain.write().unwrap().subscribe_tip_changes();
+        let storage = xblockchain_storage::Storage::init(&storage_config).expect("cannot open the storage");
+        let shutdown = tasks.shutdown_handle();
+        tasks.task_create_future("index", index::run(Arc::clone(&index), storage, genesis_data.genesis_prev.clone(), tips, shutdown));
+        index
+    });
+
+    // the status of the transactions of the pool, until they are final
+    let tracker = Arc::new(RwLock::new(tx_status::Tracker::new()));
+    {
+        let tips = blockchain.write().unwrap().subscribe_tip_changes();
+        let storage = xblockchain_storage::Storage::init(&storage_config).expect("cannot open the storage");
+        let shutdown = tasks.shutdown_handle();
+        tasks.task_create_future("transaction-status", tx_status::run(
+            Arc::clone(&tracker),
+            storage,
+            Arc::clone(&blockchain),
+            Arc::clone(&tpool),
+            tips,
+            shutdown,
+        ));
     };
 
     let client_task = {
-        let blockchain = Arc::clone(&blockchain);
-        tasks.task_create_with_inputs("client-query", move |r| client_task(blockchain, r))
+        let client = client::ClientTask::new(Arc::clone(&blockchain), &storage_config, index, tracker);
+        tasks.task_create_with_inputs("client-query", TASK_RESTART_POLICY, move |query| {
+            client.handle(query)
+        })
     };
 
     // ** TODO **
@@ -200,26 +417,72 @@ fn main() {
         let transaction_msgbox = transaction_task.clone();
         let block_msgbox = block_task.clone();
         let config = settings.network.clone();
+        let reputation = Arc::clone(&reputation);
+        let peers = peers.clone();
         let channels = network::Channels {
             client_box:      client_msgbox,
             transaction_box: transaction_msgbox,
             block_box:       block_msgbox,
         };
-        tasks.task_create("network", move || {
-            network::run(config, channels);
-        });
+        let listeners = match network::bind_listeners(&config) {
+            Ok(listeners) => listeners,
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        };
+        let shutdown = tasks.shutdown_handle();
+        let relay = relay.clone();
+        tasks.task_create_future("network", network::run(config, listeners, channels, peers, reputation, relay, shutdown));
+    };
+
+    {
+        let bootstrap = Bootstrap::new(
+            Arc::clone(&blockchain),
+            peers.clone(),
+            Arc::clone(&reputation),
+            sync_status.clone(),
+            settings.checkpoint.clone(),
+            settings.network.trusted_peers.iter().map(|peer| peer.connection.clone()).collect(),
+            tasks.shutdown_handle(),
+        );
+        tasks.task_create_future("bootstrap", bootstrap.run());
     };
 
     {
         let tpool = Arc::clone(&tpool);
+        let blockchain = Arc::clone(&blockchain);
         let clock = clock.clone();
-        tasks.task_create("leadership", move || leadership_task(tpool, clock));
+        let sync_status = sync_status.clone();
+        let block_box = block_task.clone();
+        let sync_distance = settings.sync_distance;
+        let max_block_size = block_template::read_max_block_size(&settings.genesis_data_config).unwrap_or_else(|err| {
+            error!("cannot read the maximum block size from the genesis configuration: {}", err);
+            std::process::exit(1)
+        });
+        let shutdown = tasks.shutdown_handle();
+        tasks.task_create_future("leadership", leadership_task(
+            leader, tpool, blockchain, block_box, clock, sync_status, sync_distance, max_block_size, shutdown,
+        ));
     };
 
     // periodically cleanup (custom):
     //   storage cleanup/packing
     //   tpool.gc()
 
-    // FIXME some sort of join so that the main thread does something ...
-    tasks.join();
+    // # Shutdown
+    //
+    // on SIGINT or SIGTERM: the network closes the connections, the
+    // tasks process the messages already queued, then the tip is
+    // flushed to the storage.
+    if let Err(err) = utils::signal::wait_termination() {
+        error!("cannot wait for the termination signals: {}", err);
+    }
+    info!("shutting down");
+    for task in tasks.health() {
+        info!("task {}: {:?}, {} restarts, mailbox {:?}", task.name, task.status, task.restarts, task.mailbox);
+    }
+    tasks.shutdown();
+    blockchain.read().unwrap().flush();
+    info!("shutdown complete");
 }
@@ -0,0 +1,349 @@
+//! the messages exchanged on the light weight connections, besides the
+//! block requests of the protocol: the peer discovery gossip and the
+//! transaction relay.
+//!
+//! The messages are encoded as the `DataMessage` of `proto/node.proto`
+//! in the protobuf wire format, the transactions are in CBOR as in the
+//! blocks.
+//!
+
+use std::{
+    io::{self, Cursor},
+    net::SocketAddr,
+};
+
+use cbor_event::{de::Deserializer, se::Serializer};
+
+use blockcfg::{Transaction, TransactionId};
+use intercom::ProposeStatus;
+
+use super::gossip::Gossip;
+
+#[derive(Debug, Clone)]
+pub enum DataMessage {
+    /// the peers known by the sender
+    Gossip(Gossip),
+    /// the ids of transactions the sender has in its pool
+    ProposeTransactions(Vec<TransactionId>),
+    /// the status of the proposed transactions for the receiver, only
+    /// the new ones are sent
+    ProposeTransactionsResponse(Vec<(TransactionId, ProposeStatus)>),
+    /// the transactions the receiver answered are new to it
+    Transactions(Vec<Transaction>),
+}
+
+/// the fields of the `message` oneof of `DataMessage`
+const FIELD_GOSSIP: u32 = 1;
+const FIELD_PROPOSE_TRANSACTIONS: u32 = 2;
+const FIELD_PROPOSE_TRANSACTIONS_RESPONSE: u32 = 3;
+const FIELD_TRANSACTIONS: u32 = 4;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_64BIT: u64 = 1;
+const WIRE_LENGTH_DELIMITED: u64 = 2;
+const WIRE_32BIT: u64 = 5;
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn ::std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_varint_field(out: &mut Vec<u8>, field: u32, value: u64) {
+    put_varint(out, u64::from(field) << 3 | WIRE_VARINT);
+    put_varint(out, value);
+}
+
+fn put_bytes_field(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_varint(out, u64::from(field) << 3 | WIRE_LENGTH_DELIMITED);
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Value<'a> {
+    fn varint(self) -> io::Result<u64> {
+        match self {
+            Value::Varint(value) => Ok(value),
+            Value::Bytes(_) => Err(invalid_data("varint expected")),
+        }
+    }
+
+    fn bytes(self) -> io::Result<&'a [u8]> {
+        match self {
+            Value::Bytes(bytes) => Ok(bytes),
+            Value::Varint(_) => Err(invalid_data("length delimited field expected")),
+        }
+    }
+}
+
+/// the fields of an encoded message
+struct Fields<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Fields { bytes: bytes }
+    }
+
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut value = 0;
+        let mut shift = 0;
+        while shift < 64 {
+            let (byte, rest) = self.bytes.split_first().ok_or_else(|| invalid_data("truncated varint"))?;
+            self.bytes = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+        Err(invalid_data("varint too long"))
+    }
+
+    fn take(&mut self, len: u64) -> io::Result<&'a [u8]> {
+        if len > self.bytes.len() as u64 {
+            return Err(invalid_data("truncated field"));
+        }
+        let (value, rest) = self.bytes.split_at(len as usize);
+        self.bytes = rest;
+        Ok(value)
+    }
+
+    /// the next field and its value, the fields of the fixed size wire
+    /// types are skipped as none of the messages have some
+    fn next(&mut self) -> io::Result<Option<(u32, Value<'a>)>> {
+        while !self.bytes.is_empty() {
+            let key = self.varint()?;
+            let field = (key >> 3) as u32;
+            match key & 7 {
+                WIRE_VARINT => return Ok(Some((field, Value::Varint(self.varint()?)))),
+                WIRE_LENGTH_DELIMITED => {
+                    let len = self.varint()?;
+                    return Ok(Some((field, Value::Bytes(self.take(len)?))));
+                }
+                WIRE_64BIT => {
+                    self.take(8)?;
+                }
+                WIRE_32BIT => {
+                    self.take(4)?;
+                }
+                wire_type => return Err(invalid_data(format!("unsupported wire type {}", wire_type))),
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn decode_address(value: Value) -> io::Result<SocketAddr> {
+    let text = ::std::str::from_utf8(value.bytes()?).map_err(invalid_data)?;
+    text.parse().map_err(|err| invalid_data(format!("invalid address {}: {}", text, err)))
+}
+
+fn decode_id(value: Value) -> io::Result<TransactionId> {
+    TransactionId::try_from_slice(value.bytes()?).map_err(|err| invalid_data(format!("invalid transaction id: {:?}", err)))
+}
+
+fn decode_status(value: Value) -> io::Result<ProposeStatus> {
+    match value.varint()? {
+        0 => Ok(ProposeStatus::New),
+        1 => Ok(ProposeStatus::AlreadyExists),
+        status => Err(invalid_data(format!("unknown transaction status {}", status))),
+    }
+}
+
+fn encode_transaction(tx: &Transaction) -> Vec<u8> {
+    let mut serializer = Serializer::new_vec();
+    serializer.serialize(tx).expect("transaction serialization cannot fail");
+    let mut transaction = Vec::new();
+    put_bytes_field(&mut transaction, 1, &serializer.finalize());
+    transaction
+}
+
+fn decode_transaction(value: Value) -> io::Result<Transaction> {
+    let mut content = None;
+    let mut fields = Fields::new(value.bytes()?);
+    while let Some((field, value)) = fields.next()? {
+        if field == 1 {
+            content = Some(value.bytes()?);
+        }
+    }
+    let content = content.unwrap_or(&[]);
+    Deserializer::from(Cursor::new(content))
+        .deserialize::<Transaction>()
+        .map_err(|err| invalid_data(format!("invalid transaction: {:?}", err)))
+}
+
+impl DataMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = Vec::new();
+        let field = match self {
+            DataMessage::Gossip(gossip) => {
+                for address in gossip.listen.iter() {
+                    put_bytes_field(&mut message, 1, address.to_string().as_bytes());
+                }
+                for address in gossip.peers.iter() {
+                    put_bytes_field(&mut message, 2, address.to_string().as_bytes());
+                }
+                FIELD_GOSSIP
+            }
+            DataMessage::ProposeTransactions(ids) => {
+                for id in ids.iter() {
+                    put_bytes_field(&mut message, 1, id.as_ref());
+                }
+                FIELD_PROPOSE_TRANSACTIONS
+            }
+            DataMessage::ProposeTransactionsResponse(items) => {
+                for (id, status) in items.iter() {
+                    let mut item = Vec::new();
+                    put_bytes_field(&mut item, 1, id.as_ref());
+                    put_varint_field(&mut item, 2, *status as u64);
+                    put_bytes_field(&mut message, 1, &item);
+                }
+                FIELD_PROPOSE_TRANSACTIONS_RESPONSE
+            }
+            DataMessage::Transactions(transactions) => {
+                for tx in transactions.iter() {
+                    put_bytes_field(&mut message, 1, &encode_transaction(tx));
+                }
+                FIELD_TRANSACTIONS
+            }
+        };
+        let mut bytes = Vec::with_capacity(message.len() + 8);
+        put_bytes_field(&mut bytes, field, &message);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut envelope = Fields::new(bytes);
+        let (field, message) = match envelope.next()? {
+            Some((field, value)) => (field, value.bytes()?),
+            None => return Err(invalid_data("empty data message")),
+        };
+        if envelope.next()?.is_some() {
+            return Err(invalid_data("more than one message in the data message"));
+        }
+
+        let mut fields = Fields::new(message);
+        match field {
+            FIELD_GOSSIP => {
+                let mut gossip = Gossip { listen: Vec::new(), peers: Vec::new() };
+                while let Some((field, value)) = fields.next()? {
+                    match field {
+                        1 => gossip.listen.push(decode_address(value)?),
+                        2 => gossip.peers.push(decode_address(value)?),
+                        _ => {}
+                    }
+                }
+                Ok(DataMessage::Gossip(gossip))
+            }
+            FIELD_PROPOSE_TRANSACTIONS => {
+                let mut ids = Vec::new();
+                while let Some((field, value)) = fields.next()? {
+                    if field == 1 {
+                        ids.push(decode_id(value)?);
+                    }
+                }
+                Ok(DataMessage::ProposeTransactions(ids))
+            }
+            FIELD_PROPOSE_TRANSACTIONS_RESPONSE => {
+                let mut items = Vec::new();
+                while let Some((field, value)) = fields.next()? {
+                    if field != 1 {
+                        continue;
+                    }
+                    let mut id = None;
+                    // NEW is the default value, not encoded
+                    let mut status = ProposeStatus::New;
+                    let mut item = Fields::new(value.bytes()?);
+                    while let Some((field, value)) = item.next()? {
+                        match field {
+                            1 => id = Some(decode_id(value)?),
+                            2 => status = decode_status(value)?,
+                            _ => {}
+                        }
+                    }
+                    let id = id.ok_or_else(|| invalid_data("proposed transaction without id"))?;
+                    items.push((id, status));
+                }
+                Ok(DataMessage::ProposeTransactionsResponse(items))
+            }
+            FIELD_TRANSACTIONS => {
+                let mut transactions = Vec::new();
+                while let Some((field, value)) = fields.next()? {
+                    if field == 1 {
+                        transactions.push(decode_transaction(value)?);
+                    }
+                }
+                Ok(DataMessage::Transactions(transactions))
+            }
+            field => Err(invalid_data(format!("unknown data message {}", field))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varints_round_trip() {
+        for value in &[0, 1, 127, 128, 300, u64::from(u32::max_value()), u64::max_value()] {
+            let mut bytes = Vec::new();
+            put_varint(&mut bytes, *value);
+            let mut fields = Fields::new(&bytes);
+            assert_eq!(fields.varint().unwrap(), *value);
+            assert!(fields.bytes.is_empty());
+        }
+    }
+
+    #[test]
+    fn gossip_round_trips() {
+        let gossip = Gossip {
+            listen: vec!["127.0.0.1:3000".parse().unwrap()],
+            peers: vec!["10.0.0.1:3000".parse().unwrap(), "[::1]:3001".parse().unwrap()],
+        };
+        match DataMessage::from_bytes(&DataMessage::Gossip(gossip.clone()).to_bytes()).unwrap() {
+            DataMessage::Gossip(decoded) => {
+                assert_eq!(decoded.listen, gossip.listen);
+                assert_eq!(decoded.peers, gossip.peers);
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[test]
+    fn messages_follow_the_proto_encoding() {
+        // field 2 (propose_transactions), length delimited and empty
+        assert_eq!(DataMessage::ProposeTransactions(Vec::new()).to_bytes(), vec![0x12, 0x00]);
+        match DataMessage::from_bytes(&[0x12, 0x00]).unwrap() {
+            DataMessage::ProposeTransactions(ids) => assert!(ids.is_empty()),
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[test]
+    fn invalid_messages_are_refused() {
+        assert!(DataMessage::from_bytes(&[]).is_err());
+        // truncated length
+        assert!(DataMessage::from_bytes(&[0x12, 0x05, 0x00]).is_err());
+        // unknown message
+        assert!(DataMessage::from_bytes(&[0x2a, 0x00]).is_err());
+        // two messages
+        assert!(DataMessage::from_bytes(&[0x12, 0x00, 0x12, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,423 @@
+//! peer discovery
+//!
+//! The nodes periodically send to each of their connected peers the
+//! addresses they are listening to and a selection of the best peers
+//! they know of. The received addresses are recorded in the peer
+//! table, which the node uses to dial new peers when it does not have
+//! enough outbound connections.
+//!
+//! The peer table keeps, for each known address, the last time we
+//! successfully connected to it and a quality score: successful
+//! connections increase the score, failed ones decrease it until the
+//! address is forgotten. The table is persisted so a restarted node
+//! does not depend only on its configured peers.
+//!
+//! The gossiped addresses are not trusted: a peer only advertises
+//! addresses at least as public as its own (a public peer cannot send
+//! us to loopback or private addresses), and the addresses learnt from
+//! one peer are limited so a single peer cannot fill the table and
+//! choose the peers we dial.
+//!
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_yaml;
+
+/// maximum number of addresses sent or accepted in one gossip message
+pub const MAX_GOSSIP_PEERS: usize = 32;
+
+/// maximum number of addresses kept in the peer table
+const MAX_PEER_TABLE_SIZE: usize = 1024;
+
+/// maximum number of addresses learnt from the gossip of the same peer
+/// (by IP address) kept in the peer table
+const MAX_PEERS_PER_SOURCE: usize = 64;
+
+const SCORE_INITIAL: i32 = 0;
+const SCORE_SUCCESS: i32 = 10;
+const SCORE_FAILURE: i32 = -20;
+const SCORE_MAX: i32 = 100;
+/// the addresses whose score drop below this value are forgotten
+const SCORE_MIN: i32 = -100;
+
+/// the gossip message exchanged between the nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Gossip {
+    /// the addresses the sender accepts connections on
+    pub listen: Vec<SocketAddr>,
+    /// the best peers the sender knows of
+    pub peers: Vec<SocketAddr>,
+}
+
+/// how far an address is reachable from, the variants are ordered from
+/// the narrowest to the widest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Scope {
+    Loopback,
+    Private,
+    Public,
+}
+
+fn scope(ip: &IpAddr) -> Scope {
+    match ip {
+        IpAddr::V4(ip) if ip.is_loopback() => Scope::Loopback,
+        IpAddr::V4(ip) if ip.is_private() || ip.is_link_local() => Scope::Private,
+        IpAddr::V6(ip) if ip.is_loopback() => Scope::Loopback,
+        // unique local (fc00::/7) and link local (fe80::/10)
+        IpAddr::V6(ip) if ip.segments()[0] & 0xfe00 == 0xfc00 || ip.segments()[0] & 0xffc0 == 0xfe80 => {
+            Scope::Private
+        }
+        _ => Scope::Public,
+    }
+}
+
+/// check the address can be dialed, and is not narrower than the
+/// address of the peer advertising it. The peers connected by other
+/// means than a socket (e.g. unix domain sockets) are local.
+fn is_acceptable(address: &SocketAddr, remote: Option<SocketAddr>) -> bool {
+    let ip = address.ip();
+    let dialable = match ip {
+        IpAddr::V4(ip) => !ip.is_unspecified() && !ip.is_broadcast() && !ip.is_multicast() && !ip.is_documentation(),
+        IpAddr::V6(ip) => !ip.is_unspecified() && !ip.is_multicast(),
+    };
+    let source = remote.map(|remote| scope(&remote.ip())).unwrap_or(Scope::Loopback);
+    dialable && address.port() != 0 && scope(&ip) >= source
+}
+
+impl Gossip {
+    /// the addresses advertised by the remote peer. The listen
+    /// addresses that are not routable (e.g. `0.0.0.0`) are replaced
+    /// with the IP address the remote peer connected from, the
+    /// addresses we should not dial are dropped.
+    pub fn addresses(&self, remote: Option<SocketAddr>) -> Vec<SocketAddr> {
+        let listen = self.listen.iter().filter_map(|addr| {
+            if addr.ip().is_unspecified() {
+                remote.map(|remote| SocketAddr::new(remote.ip(), addr.port()))
+            } else {
+                Some(*addr)
+            }
+        });
+        listen
+            .chain(self.peers.iter().cloned())
+            .filter(|addr| is_acceptable(addr, remote))
+            .take(MAX_GOSSIP_PEERS)
+            .collect()
+    }
+}
+
+/// what we know about a peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerRecord {
+    pub address: SocketAddr,
+    /// last time (in seconds since the UNIX epoch) we successfully
+    /// connected to this peer, `None` if we never did.
+    pub last_seen: Option<u64>,
+    pub score: i32,
+    /// the IP address of the peer whose gossip we learnt this address
+    /// from, `None` for the addresses we connected to.
+    #[serde(default)]
+    pub source: Option<IpAddr>,
+}
+
+#[derive(Default)]
+pub struct PeerTable {
+    peers: BTreeMap<SocketAddr, PeerRecord>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl PeerTable {
+    pub fn new() -> Self {
+        PeerTable::default()
+    }
+
+    /// load the peer table from the given file, an absent file is an
+    /// empty table.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(PeerTable::new())
+            }
+            Err(err) => return Err(err),
+        };
+        let records: Vec<PeerRecord> = serde_yaml::from_reader(file)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(PeerTable {
+            peers: records
+                .into_iter()
+                .map(|record| (record.address, record))
+                .collect(),
+        })
+    }
+
+    /// save the peer table in the given file. The table is written in
+    /// a temporary file first so an interrupted write does not lose
+    /// the previous table.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        {
+            let file = fs::File::create(&tmp)?;
+            let records = self.peers.values().collect::<Vec<_>>();
+            serde_yaml::to_writer(file, &records)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        }
+        fs::rename(tmp, path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// record an address learnt from the gossip of the peer at the
+    /// `source` IP address. The addresses beyond the limit of the
+    /// source are ignored.
+    pub fn learn(&mut self, address: SocketAddr, source: IpAddr) {
+        if self.peers.contains_key(&address) {
+            return;
+        }
+        let learnt = self
+            .peers
+            .values()
+            .filter(|record| record.source == Some(source))
+            .count();
+        if learnt >= MAX_PEERS_PER_SOURCE {
+            return;
+        }
+        self.insert(address, Some(source));
+    }
+
+    fn insert(&mut self, address: SocketAddr, source: Option<IpAddr>) {
+        if self.peers.len() >= MAX_PEER_TABLE_SIZE && !self.evict_worst() {
+            return;
+        }
+        self.peers.insert(
+            address,
+            PeerRecord {
+                address: address,
+                last_seen: None,
+                score: SCORE_INITIAL,
+                source: source,
+            },
+        );
+    }
+
+    /// we successfully connected to the peer, the address no longer
+    /// counts against the limit of the peer we learnt it from.
+    pub fn connected(&mut self, address: SocketAddr) {
+        if !self.peers.contains_key(&address) {
+            self.insert(address, None);
+        }
+        if let Some(record) = self.peers.get_mut(&address) {
+            record.last_seen = Some(now());
+            record.score = ::std::cmp::min(record.score + SCORE_SUCCESS, SCORE_MAX);
+            record.source = None;
+        }
+    }
+
+    /// we failed to connect to the peer, or it stopped responding
+    pub fn failed(&mut self, address: SocketAddr) {
+        let forget = match self.peers.get_mut(&address) {
+            None => false,
+            Some(record) => {
+                record.score += SCORE_FAILURE;
+                record.score < SCORE_MIN
+            }
+        };
+        if forget {
+            debug!("forgetting peer {}", address);
+            self.peers.remove(&address);
+        }
+    }
+
+    /// remove the peer with the lowest score, if its score is below
+    /// the initial score of a new peer.
+    fn evict_worst(&mut self) -> bool {
+        let worst = self
+            .peers
+            .values()
+            .filter(|record| record.score < SCORE_INITIAL)
+            .min_by_key(|record| (record.score, record.last_seen))
+            .map(|record| record.address);
+        match worst {
+            Some(address) => {
+                self.peers.remove(&address);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// the known peers sorted from the best to the worst
+    fn ranked(&self) -> Vec<&PeerRecord> {
+        let mut records = self.peers.values().collect::<Vec<_>>();
+        records.sort_by(|a, b| {
+            b.score
+                .cmp(&a.score)
+                .then_with(|| b.last_seen.cmp(&a.last_seen))
+        });
+        records
+    }
+
+    /// select up to `count` peers to dial, excluding the given addresses
+    pub fn candidates(&self, exclude: &HashSet<SocketAddr>, count: usize) -> Vec<SocketAddr> {
+        self.ranked()
+            .into_iter()
+            .map(|record| record.address)
+            .filter(|address| !exclude.contains(address))
+            .take(count)
+            .collect()
+    }
+
+    /// select the peers to advertise to the other nodes: only the
+    /// peers we successfully connected to.
+    pub fn gossip(&self) -> Vec<SocketAddr> {
+        self.ranked()
+            .into_iter()
+            .filter(|record| record.last_seen.is_some() && record.score >= SCORE_INITIAL)
+            .map(|record| record.address)
+            .take(MAX_GOSSIP_PEERS)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn gossip_replaces_unspecified_listen_address() {
+        let gossip = Gossip {
+            listen: vec![addr("0.0.0.0:3000")],
+            peers: vec![],
+        };
+        assert_eq!(gossip.addresses(Some(addr("8.8.8.8:4000"))), vec![addr("8.8.8.8:3000")]);
+    }
+
+    #[test]
+    fn gossip_drops_narrower_addresses() {
+        let gossip = Gossip {
+            listen: vec![],
+            peers: vec![
+                addr("127.0.0.1:3000"),
+                addr("192.168.1.2:3000"),
+                addr("[fd00::1]:3000"),
+                addr("1.2.3.4:3000"),
+                addr("1.2.3.4:0"),
+                addr("224.0.0.1:3000"),
+            ],
+        };
+        assert_eq!(gossip.addresses(Some(addr("8.8.8.8:4000"))), vec![addr("1.2.3.4:3000")]);
+        assert_eq!(
+            gossip.addresses(Some(addr("10.0.0.1:4000"))),
+            vec![addr("192.168.1.2:3000"), addr("[fd00::1]:3000"), addr("1.2.3.4:3000")]
+        );
+        assert_eq!(gossip.addresses(Some(addr("127.0.0.1:4000"))).len(), 4);
+    }
+
+    #[test]
+    fn gossip_is_capped() {
+        let gossip = Gossip {
+            listen: vec![],
+            peers: (1..100).map(|port| SocketAddr::new(ip("1.2.3.4"), port)).collect(),
+        };
+        assert_eq!(gossip.addresses(None).len(), MAX_GOSSIP_PEERS);
+    }
+
+    #[test]
+    fn learn_is_limited_per_source() {
+        let mut table = PeerTable::new();
+        for port in 1..1000 {
+            table.learn(SocketAddr::new(ip("1.2.3.4"), port), ip("5.6.7.8"));
+        }
+        assert_eq!(table.len(), MAX_PEERS_PER_SOURCE);
+        table.learn(addr("1.2.3.5:1"), ip("5.6.7.9"));
+        assert_eq!(table.len(), MAX_PEERS_PER_SOURCE + 1);
+
+        // a connected address no longer counts for its source
+        table.connected(addr("1.2.3.4:1"));
+        table.learn(addr("1.2.3.6:1"), ip("5.6.7.8"));
+        assert_eq!(table.len(), MAX_PEERS_PER_SOURCE + 2);
+    }
+
+    #[test]
+    fn scores_rank_and_forget_peers() {
+        let mut table = PeerTable::new();
+        let good = addr("1.1.1.1:1");
+        let bad = addr("2.2.2.2:2");
+        let new = addr("3.3.3.3:3");
+        table.learn(bad, ip("9.9.9.9"));
+        table.learn(new, ip("9.9.9.9"));
+        table.connected(good);
+        table.failed(bad);
+
+        let none = HashSet::new();
+        assert_eq!(table.candidates(&none, 10), vec![good, new, bad]);
+        assert_eq!(table.candidates(&none, 1), vec![good]);
+        let excluded = vec![good].into_iter().collect();
+        assert_eq!(table.candidates(&excluded, 10), vec![new, bad]);
+        // only the peers we connected to are advertised
+        assert_eq!(table.gossip(), vec![good]);
+
+        for _ in 0..5 {
+            table.failed(bad);
+        }
+        assert_eq!(table.candidates(&none, 10), vec![good, new]);
+    }
+
+    #[test]
+    fn full_table_evicts_the_worst_peer() {
+        let mut table = PeerTable::new();
+        for n in 0..MAX_PEER_TABLE_SIZE {
+            table.connected(SocketAddr::new(ip("1.2.3.4"), n as u16 + 1));
+        }
+        table.learn(addr("5.5.5.5:5"), ip("9.9.9.9"));
+        assert_eq!(table.len(), MAX_PEER_TABLE_SIZE);
+        assert!(!table.peers.contains_key(&addr("5.5.5.5:5")));
+
+        table.failed(addr("1.2.3.4:1"));
+        table.failed(addr("1.2.3.4:1"));
+        table.learn(addr("5.5.5.5:5"), ip("9.9.9.9"));
+        assert_eq!(table.len(), MAX_PEER_TABLE_SIZE);
+        assert!(table.peers.contains_key(&addr("5.5.5.5:5")));
+        assert!(!table.peers.contains_key(&addr("1.2.3.4:1")));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = env::temp_dir().join(format!("xchain-peer-table-{}.yaml", process::id()));
+        let mut table = PeerTable::new();
+        table.connected(addr("1.1.1.1:1"));
+        table.learn(addr("2.2.2.2:2"), ip("9.9.9.9"));
+        table.save(&path).unwrap();
+
+        let loaded = PeerTable::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.gossip(), vec![addr("1.1.1.1:1")]);
+        assert_eq!(loaded.peers[&addr("2.2.2.2:2")].source, Some(ip("9.9.9.9")));
+
+        let missing = env::temp_dir().join(format!("xchain-peer-table-missing-{}.yaml", process::id()));
+        assert_eq!(PeerTable::load(&missing).unwrap().len(), 0);
+    }
+}
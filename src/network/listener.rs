@@ -0,0 +1,162 @@
+//! accepting inbound connections
+//!
+//! The listeners are bound before the network starts so an invalid
+//! or already used address is reported at startup instead of
+//! panicking inside the runtime. Once bound, errors returned while
+//! accepting a connection are not fatal: the listener keeps running
+//! and, when the error is due to resource exhaustion (too many open
+//! files for example), waits a bit before trying again.
+//!
+
+use std::{
+    io,
+    sync::{Arc, atomic::{AtomicUsize, Ordering}},
+    time::{Duration, Instant},
+};
+
+use futures::prelude::*;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::timer::Delay;
+
+/// the first delay to wait for after a failed accept
+const BACKOFF_MIN: Duration = Duration::from_millis(10);
+/// the delay between 2 attempts to accept a connection won't grow
+/// passed this value
+const BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// common interface of the different listeners (TCP or unix socket)
+pub trait Accept {
+    type Stream;
+
+    fn poll_accept_stream(&mut self) -> Poll<Self::Stream, io::Error>;
+}
+
+impl Accept for TcpListener {
+    type Stream = TcpStream;
+
+    fn poll_accept_stream(&mut self) -> Poll<Self::Stream, io::Error> {
+        self.poll_accept().map(|ready| ready.map(|(stream, _)| stream))
+    }
+}
+
+#[cfg(unix)]
+impl Accept for UnixListener {
+    type Stream = UnixStream;
+
+    fn poll_accept_stream(&mut self) -> Poll<Self::Stream, io::Error> {
+        self.poll_accept().map(|ready| ready.map(|(stream, _)| stream))
+    }
+}
+
+/// the stream of inbound connections of a listener.
+///
+/// Unlike the `incoming()` stream of the tokio listeners, this stream
+/// does not terminate on the first error.
+pub struct Incoming<L> {
+    listener: L,
+    delay: Option<Delay>,
+    backoff: Duration,
+}
+
+impl<L: Accept> Incoming<L> {
+    pub fn new(listener: L) -> Self {
+        Incoming {
+            listener: listener,
+            delay: None,
+            backoff: BACKOFF_MIN,
+        }
+    }
+}
+
+/// the errors that are specific to the connection being accepted,
+/// we can try to accept the next connection straight away.
+fn is_connection_error(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted => true,
+        _ => false,
+    }
+}
+
+impl<L: Accept> Stream for Incoming<L> {
+    type Item = L::Stream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(ref mut delay) = self.delay {
+                match delay.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(())) => {}
+                    Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+                }
+            }
+            self.delay = None;
+
+            match self.listener.poll_accept_stream() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(stream)) => {
+                    self.backoff = BACKOFF_MIN;
+                    return Ok(Async::Ready(Some(stream)));
+                }
+                Err(ref err) if is_connection_error(err) => {
+                    debug!("error while accepting connection: {}", err);
+                }
+                Err(err) => {
+                    warn!(
+                        "error while accepting connection: {}, retrying in {:?}",
+                        err, self.backoff
+                    );
+                    self.delay = Some(Delay::new(Instant::now() + self.backoff));
+                    self.backoff = ::std::cmp::min(self.backoff * 2, BACKOFF_MAX);
+                }
+            }
+        }
+    }
+}
+
+/// keep track of the number of inbound connections, shared between
+/// all the listeners.
+#[derive(Clone)]
+pub struct InboundConnections {
+    count: Arc<AtomicUsize>,
+    max: usize,
+}
+
+/// a slot in the inbound connections, released on drop.
+pub struct InboundSlot(Arc<AtomicUsize>);
+
+impl InboundConnections {
+    pub fn new(max: usize) -> Self {
+        InboundConnections {
+            count: Arc::new(AtomicUsize::new(0)),
+            max: max,
+        }
+    }
+
+    /// reserve a slot for a new inbound connection, returns `None`
+    /// if the maximum number of inbound connections is reached.
+    pub fn try_acquire(&self) -> Option<InboundSlot> {
+        let previous = self.count.fetch_add(1, Ordering::SeqCst);
+        if previous >= self.max {
+            self.count.fetch_sub(1, Ordering::SeqCst);
+            None
+        } else {
+            Some(InboundSlot(self.count.clone()))
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for InboundSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
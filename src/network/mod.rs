@@ -5,20 +5,64 @@
 //! transactions...);
 //!
 
-use std::{net::{SocketAddr}, sync::{Arc}, time::{Duration}};
+mod data;
+mod gossip;
+mod listener;
+mod peers;
+mod pending;
+mod query;
+mod relay;
+#[cfg(unix)]
+mod unix;
+
+use std::{
+    collections::HashSet, fmt, io, net::{SocketAddr}, path::{Path},
+    sync::{Arc, Mutex, RwLock}, time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::path::{PathBuf};
 
 use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::timer::Interval;
 use protocol::{Inbound, Message, Connection};
 use futures::{future, stream::{self, Stream}, sync::mpsc, prelude::{*}};
-use intercom::{ClientMsg, TransactionMsg, BlockMsg};
+use intercom::{reply_channel, ClientMsg, ClientQuery, ProposeStatus, TransactionMsg, BlockMsg, PeerMsg};
 
-use utils::task::{TaskMessageBox};
+use reputation::{Offence, ReputationR};
+use utils::task::{Shutdown, TaskMessageBox};
 use settings::network::{self, Peer, Listen};
 
-/// all the different channels the network may need to talk to
+use self::listener::{Incoming, InboundConnections, InboundSlot};
+use self::pending::PendingRequests;
+use self::query::QueryReply;
+use self::gossip::Gossip;
+use self::data::DataMessage;
+use self::relay::{ANNOUNCE_BATCH, RELAY_INTERVAL};
+
+pub use self::gossip::{PeerRecord, PeerTable};
+pub use self::peers::{ConnectedPeers, Direction, PeerHandle, PeerId};
+pub use self::relay::TransactionRelay;
+
+/// how often the connections check for the requests that timed out
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// how often the connections send the known peers to the remote node
+const GOSSIP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// how often we check if we need to dial more peers
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// the timeout of the connections to the peers found by the discovery
+const DISCOVERED_PEER_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// all the different channels the network may need to talk to. The
+/// block mailbox is bounded: a connection stops reading from the peer
+/// while it waits for room for a block.
 #[derive(Clone)]
 pub struct Channels {
-    pub client_box:      TaskMessageBox<ClientMsg>,
+    pub client_box:      TaskMessageBox<ClientQuery>,
     pub transaction_box: TaskMessageBox<TransactionMsg>,
     pub block_box:       TaskMessageBox<BlockMsg>,
 }
@@ -27,6 +71,13 @@ pub struct Channels {
 pub struct GlobalState {
     pub config:   Arc<network::Configuration>,
     pub channels: Channels,
+    pub inbound:  InboundConnections,
+    pub peers:    ConnectedPeers,
+    pub peer_table: Arc<RwLock<PeerTable>>,
+    /// the addresses we are currently trying to connect to
+    pub dialing:  Arc<Mutex<HashSet<SocketAddr>>>,
+    pub reputation: ReputationR,
+    pub relay:    TransactionRelay,
 }
 
 #[derive(Clone)]
@@ -38,6 +89,19 @@ pub struct ConnectionState {
     /// send messages too
     pub channels: Channels,
 
+    /// the currently connected peers, the connection registers
+    /// itself so the other tasks can send requests to the peer
+    pub peers: ConnectedPeers,
+
+    /// the known peers, updated with the gossip of the remote peer
+    pub peer_table: Arc<RwLock<PeerTable>>,
+
+    /// where to report the misbehaviour of the remote peer
+    pub reputation: ReputationR,
+
+    /// the transactions to announce to the remote peer
+    pub relay: TransactionRelay,
+
     /// the timeout to wait for unbefore the connection replies
     pub timeout: Duration,
 
@@ -45,110 +109,433 @@ pub struct ConnectionState {
     pub connection: network::Connection,
 
     pub connected: Option<network::Connection>,
+
+    pub direction: Direction,
 }
 impl ConnectionState {
     fn new_listen(global: &GlobalState, listen: Listen) -> Self {
         ConnectionState {
             global_network_configuration: global.config.clone(),
             channels: global.channels.clone(),
+            peers: global.peers.clone(),
+            peer_table: global.peer_table.clone(),
+            reputation: global.reputation.clone(),
+            relay: global.relay.clone(),
             timeout: listen.timeout,
             connection: listen.connection,
             connected: None,
+            direction: Direction::Inbound,
         }
     }
     fn new_peer(global: &GlobalState, peer: Peer) -> Self {
         ConnectionState {
             global_network_configuration: global.config.clone(),
             channels: global.channels.clone(),
+            peers: global.peers.clone(),
+            peer_table: global.peer_table.clone(),
+            reputation: global.reputation.clone(),
+            relay: global.relay.clone(),
             timeout: peer.timeout,
             connection: peer.connection,
             connected: None,
+            direction: Direction::Outbound,
         }
     }
     fn connected(mut self, connection: network::Connection) -> Self {
         self.connected = Some(connection);
         self
     }
+
+    /// the connection details of the remote peer
+    fn remote(&self) -> network::Connection {
+        match self.direction {
+            Direction::Inbound => self.connected.clone().unwrap_or(self.connection.clone()),
+            Direction::Outbound => self.connection.clone(),
+        }
+    }
+
+    fn remote_socket(&self) -> Option<SocketAddr> {
+        match self.remote() {
+            network::Connection::Socket(addr) => Some(addr),
+            #[cfg(unix)]
+            network::Connection::Unix(_) => None,
+        }
+    }
+
+    /// build the gossip to send to the remote peer
+    fn gossip(&self) -> Gossip {
+        let listen = self.global_network_configuration.listen_to.iter()
+            .filter_map(|listen| match listen.connection {
+                network::Connection::Socket(addr) => Some(addr),
+                #[cfg(unix)]
+                network::Connection::Unix(_) => None,
+            })
+            .collect();
+        let remote = self.remote_socket();
+        let peers = self.peer_table.read().unwrap().gossip()
+            .into_iter()
+            .filter(|addr| Some(*addr) != remote)
+            .collect();
+        Gossip { listen: listen, peers: peers }
+    }
+}
+
+/// the default maximum number of inbound connections the node accepts
+/// at the same time, connections received above this limit are dropped.
+pub const DEFAULT_MAX_INBOUND_CONNECTIONS: usize = 256;
+
+/// the default number of peers the node tries to keep connected to
+pub const DEFAULT_OUTBOUND_CONNECTIONS: usize = 8;
+
+/// error while starting the network
+#[derive(Debug)]
+pub enum Error {
+    /// the node cannot listen to the given address
+    Listen(network::Connection, io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Listen(connection, err) => {
+                write!(f, "cannot listen to {}: {}", connection, err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn cause(&self) -> Option<&std::error::Error> {
+        match self {
+            Error::Listen(_, err) => Some(err),
+        }
+    }
+}
+
+/// a listener bound to its address, ready to accept connections
+pub enum Listener {
+    Socket(TcpListener, Listen),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf, Listen),
+}
+
+/// bind all the listeners of the configuration.
+///
+/// This is done before starting the network so the errors (address
+/// already in use, not enough privileges...) are reported at startup.
+pub fn bind_listeners(config: &network::Configuration) -> Result<Vec<Listener>, Error> {
+    config.listen_to.iter().cloned().map(bind_listener).collect()
+}
+
+fn bind_listener(listen: Listen) -> Result<Listener, Error> {
+    match listen.connection.clone() {
+        network::Connection::Socket(sockaddr) => {
+            match TcpListener::bind(&sockaddr) {
+                Ok(listener) => Ok(Listener::Socket(listener, listen)),
+                Err(err) => Err(Error::Listen(listen.connection, err)),
+            }
+        },
+        #[cfg(unix)]
+        network::Connection::Unix(path) => {
+            let bound = unix::remove_stale_socket(&path)
+                .and_then(|()| UnixListener::bind(&path))
+                .and_then(|listener| {
+                    unix::set_socket_permissions(&path, unix::DEFAULT_SOCKET_PERMISSIONS)
+                        .map(|()| listener)
+                });
+            match bound {
+                Ok(listener) => Ok(Listener::Unix(listener, path, listen)),
+                Err(err) => Err(Error::Listen(listen.connection, err)),
+            }
+        },
+    }
 }
 
 pub fn run( config: network::Configuration
+          , listeners: Vec<Listener>
           , channels: Channels
-          )
+          , peers: ConnectedPeers
+          , reputation: ReputationR
+          , relay: TransactionRelay
+          , shutdown: Shutdown
+          ) -> impl future::Future<Item = (), Error = ()>
 {
+    let peer_table = match config.peer_table {
+        None => PeerTable::new(),
+        Some(ref path) => PeerTable::load(path).unwrap_or_else(|err| {
+            warn!("cannot load the peer table from {}: {}", path.display(), err);
+            PeerTable::new()
+        }),
+    };
+    info!("{} known peers", peer_table.len());
+
     let arc_config = Arc::new(config.clone());
     let state = GlobalState {
         config:   arc_config,
         channels: channels,
+        inbound:  InboundConnections::new(config.max_inbound_connections),
+        peers:    peers,
+        peer_table: Arc::new(RwLock::new(peer_table)),
+        dialing:  Arc::new(Mutex::new(HashSet::new())),
+        reputation: reputation,
+        relay:    relay,
     };
 
     let state_listener = state.clone();
-    // open the port for listenting/accepting other peers to connect too
-    let listener = stream::iter_ok(config.listen_to).for_each(move |listen| {
-        match listen.connection {
-            network::Connection::Socket(sockaddr) => {
-                run_listen_socket(sockaddr, listen, state_listener.clone())
+    // start accepting other peers to connect too
+    let listener = stream::iter_ok(listeners).for_each(move |listener| {
+        match listener {
+            Listener::Socket(listener, listen) => {
+                run_listen_socket(listener, listen, state_listener.clone())
             },
             #[cfg(unix)]
-            network::Connection::Unix(path) => unimplemented!()
+            Listener::Unix(listener, path, listen) => {
+                run_listen_unix(listener, path, listen, state_listener.clone())
+            },
         }
     });
 
     let state_connection = state.clone();
-    let connections = stream::iter_ok(config.peer_nodes).for_each(move |peer| {
-        match peer.connection {
+    let peer_nodes = config.peer_nodes.into_iter().chain(config.trusted_peers);
+    let connections = stream::iter_ok(peer_nodes).for_each(move |peer| {
+        match peer.connection.clone() {
             network::Connection::Socket(sockaddr) => {
                 run_connect_socket(sockaddr, peer, state_connection.clone())
             },
             #[cfg(unix)]
-            network::Connection::Unix(path) => unimplemented!()
+            network::Connection::Unix(path) => {
+                run_connect_unix(path, peer, state_connection.clone())
+            },
         }
     });
 
-    tokio::run(connections.join(listener).map(|_| ()));
+    let discovery = run_discovery(state.clone());
+
+    let network = connections.join3(listener, discovery).map(|_| ());
+    network.select(shutdown.wait()).then(move |_| {
+        // the connections are closed when the runtime shuts down
+        info!("stopping the network");
+        if let Some(ref path) = state.config.peer_table {
+            save_peer_table(&state.peer_table, path);
+        }
+        Ok(())
+    })
+}
+
+/// periodically dial the best known peers until we have enough
+/// outbound connections, and save the peer table.
+fn run_discovery(state: GlobalState) -> impl future::Future<Item = (), Error = ()> {
+    // never dial our own listening addresses
+    let own_addresses = state.config.listen_to.iter()
+        .filter_map(|listen| match listen.connection {
+            network::Connection::Socket(addr) => Some(addr),
+            #[cfg(unix)]
+            network::Connection::Unix(_) => None,
+        })
+        .collect::<Vec<_>>();
+
+    Interval::new(Instant::now() + DISCOVERY_INTERVAL, DISCOVERY_INTERVAL)
+        .map_err(|err| error!("peer discovery timer error {}", err))
+        .for_each(move |_| {
+            state.reputation.write().unwrap().refresh();
+
+            let mut exclude = state.peers.outbound_addresses().into_iter().collect::<HashSet<_>>();
+            let connected = exclude.len();
+            let dialing = state.dialing.lock().unwrap().iter().cloned().collect::<Vec<_>>();
+            let in_progress = dialing.len();
+            exclude.extend(dialing);
+            exclude.extend(own_addresses.iter().cloned());
+
+            let target = state.config.outbound_connections;
+            if connected + in_progress < target {
+                let candidates = state.peer_table.read().unwrap()
+                    .candidates(&exclude, target - connected - in_progress)
+                    .into_iter()
+                    .filter(|addr| !is_own_address(&own_addresses, addr))
+                    .filter(|addr| !state.reputation.read().unwrap().is_banned(&addr.ip()))
+                    .collect::<Vec<_>>();
+                for addr in candidates {
+                    debug!("dialing discovered peer {}", addr);
+                    let peer = Peer {
+                        connection: network::Connection::Socket(addr),
+                        timeout: DISCOVERED_PEER_TIMEOUT,
+                    };
+                    run_connect_socket(addr, peer, state.clone());
+                }
+            }
+
+            if let Some(ref path) = state.config.peer_table {
+                save_peer_table(&state.peer_table, path);
+            }
+            Ok(())
+        })
+}
+
+/// check the address is one of our listening addresses, including
+/// the loopback addresses when we listen on all the interfaces.
+fn is_own_address(own_addresses: &[SocketAddr], addr: &SocketAddr) -> bool {
+    own_addresses.iter().any(|own| {
+        own == addr || (own.port() == addr.port() && own.ip().is_unspecified() && addr.ip().is_loopback())
+    })
+}
+
+fn save_peer_table(peer_table: &RwLock<PeerTable>, path: &Path) {
+    if let Err(err) = peer_table.read().unwrap().save(path) {
+        warn!("cannot save the peer table to {}: {}", path.display(), err)
+    }
+}
+
+/// perform the handshake with a newly accepted connection and run it
+/// in its own task. The inbound slot is released once the connection
+/// is closed.
+fn accept_connection<T>(stream: T, state: ConnectionState, slot: InboundSlot)
+  where T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static
+{
+    let remote = state.connected.clone().unwrap_or(state.connection.clone());
+    let handshake = Connection::accept(stream)
+        .map_err(move |err| error!("Rejecting NTT connection from {}: {:?}", remote, err))
+        .and_then(move |connection| run_connection(state, connection))
+        .then(move |result| {
+            drop(slot);
+            result
+        });
+    tokio::spawn(handshake);
+}
+
+/// let the OS probe idle connections so a peer that went away
+/// without closing the connection is detected.
+fn set_keepalive(stream: &TcpStream, timeout: Duration) {
+    if let Err(err) = stream.set_keepalive(Some(timeout)) {
+        warn!("cannot set the keepalive of the connection: {}", err)
+    }
 }
 
-fn run_listen_socket(sockaddr: SocketAddr, listen: Listen, state: GlobalState)
+fn run_listen_socket(listener: TcpListener, listen: Listen, state: GlobalState)
     -> tokio::executor::Spawn
 {
+    let inbound = state.inbound.clone();
+    let reputation = state.reputation.clone();
     let state = ConnectionState::new_listen(&state, listen);
 
     info!("start listening and accepting connection to {}", state.connection);
-    let server = TcpListener::bind(&sockaddr)
-        .unwrap() // TODO, handle on error to provide better error message
-        .incoming()
+    let server = Incoming::new(listener)
         .map_err(move |err| {
-            // error while receiving an incoming connection
-            // here we might need to log the error and try
-            // to listen again on the sockaddr
-            error!("Error while accepting connection from {:?}: {:?}", sockaddr, err)
+            // the listener only gives up on errors unrelated to
+            // accepting the connections (e.g. timer failure)
+            error!("Error while accepting connections: {:?}", err)
         }).for_each(move |stream| {
             // received incoming connection
-            info!("{} connected to {}", stream.peer_addr().unwrap(), stream.local_addr().unwrap());
-            let state = state.clone().connected(network::Connection::Socket(stream.peer_addr().unwrap()));
+            let peer_addr = match stream.peer_addr() {
+                Ok(peer_addr) => peer_addr,
+                Err(err) => {
+                    debug!("dropping incoming connection: {}", err);
+                    return Ok(());
+                }
+            };
+            if reputation.read().unwrap().is_banned(&peer_addr.ip()) {
+                debug!("refusing connection from banned peer {}", peer_addr);
+                return Ok(());
+            }
+            let slot = match inbound.try_acquire() {
+                Some(slot) => slot,
+                None => {
+                    warn!("too many inbound connections ({}), dropping connection from {}",
+                          inbound.count(), peer_addr);
+                    return Ok(());
+                }
+            };
+            info!("{} connected to {}", peer_addr, state.connection);
+            set_keepalive(&stream, state.timeout);
+            let state = state.clone().connected(network::Connection::Socket(peer_addr));
+            accept_connection(stream, state, slot);
+            Ok(())
+        });
+    tokio::spawn(server)
+}
+
+fn run_connect_socket(sockaddr: SocketAddr, peer: Peer, state: GlobalState)
+    -> tokio::executor::Spawn
+{
+    let peer_table = state.peer_table.clone();
+    let dialing = state.dialing.clone();
+    dialing.lock().unwrap().insert(sockaddr);
+    let state = ConnectionState::new_peer(&state, peer);
+
+    info!("connecting to {}", state.connection);
+    let err_peer_table = peer_table.clone();
+    let server = TcpStream::connect(&sockaddr)
+        .map_err(move |err| {
+            error!("Error while connecting to {:?}: {:?}", sockaddr, err);
+            err_peer_table.write().unwrap().failed(sockaddr);
+        }).and_then(move |stream| {
+            set_keepalive(&stream, state.timeout);
+            let state = state.clone().connected(network::Connection::Socket(stream.local_addr().unwrap()));
+            info!("{} connected to {}", stream.local_addr().unwrap(), stream.peer_addr().unwrap());
+            let err_peer_table = peer_table.clone();
             Connection::accept(stream)
-                .map_err(move |err| error!("Rejecting NTT connection from {:?}: {:?}", sockaddr, err))
+                .map_err(move |err| {
+                    error!("Rejecting NTT connection from {:?}: {:?}", sockaddr, err);
+                    err_peer_table.write().unwrap().failed(sockaddr);
+                })
                 .and_then(move |connection| {
+                    peer_table.write().unwrap().connected(sockaddr);
                     let state = state.clone();
                     tokio::spawn(run_connection(state, connection))
                 })
+        }).then(move |result| {
+            dialing.lock().unwrap().remove(&sockaddr);
+            result
         });
     tokio::spawn(server)
 }
 
-fn run_connect_socket(sockaddr: SocketAddr, peer: Peer, state: GlobalState)
+#[cfg(unix)]
+fn run_listen_unix(listener: UnixListener, path: PathBuf, listen: Listen, state: GlobalState)
+    -> tokio::executor::Spawn
+{
+    let inbound = state.inbound.clone();
+    let state = ConnectionState::new_listen(&state, listen);
+
+    info!("start listening and accepting connection to {}", state.connection);
+    let server = Incoming::new(listener)
+        .map_err(move |err| {
+            error!("Error while accepting connections: {:?}", err)
+        }).for_each(move |stream| {
+            let slot = match inbound.try_acquire() {
+                Some(slot) => slot,
+                None => {
+                    warn!("too many inbound connections ({}), dropping connection on {}",
+                          inbound.count(), path.display());
+                    return Ok(());
+                }
+            };
+            // received incoming connection, the remote end of a unix
+            // socket is generally unnamed so we identify the connection
+            // with the path of the socket it came through
+            info!("new connection on {}", path.display());
+            let state = state.clone().connected(network::Connection::Unix(path.clone()));
+            accept_connection(stream, state, slot);
+            Ok(())
+        });
+    tokio::spawn(server)
+}
+
+#[cfg(unix)]
+fn run_connect_unix(path: PathBuf, peer: Peer, state: GlobalState)
     -> tokio::executor::Spawn
 {
     let state = ConnectionState::new_peer(&state, peer);
 
     info!("connecting to {}", state.connection);
-    let server = TcpStream::connect(&sockaddr)
+    let err_path = path.clone();
+    let server = UnixStream::connect(&path)
         .map_err(move |err| {
-            error!("Error while connecting to {:?}: {:?}", sockaddr, err)
+            error!("Error while connecting to {}: {:?}", err_path.display(), err)
         }).and_then(move |stream| {
-            let state = state.clone().connected(network::Connection::Socket(stream.local_addr().unwrap()));
-            info!("{} connected to {}", stream.local_addr().unwrap(), stream.peer_addr().unwrap());
+            let state = state.clone().connected(network::Connection::Unix(path.clone()));
+            info!("connected to {}", path.display());
             Connection::accept(stream)
-                .map_err(move |err| error!("Rejecting NTT connection from {:?}: {:?}", sockaddr, err))
+                .map_err(move |err| error!("Rejecting NTT connection from {}: {:?}", path.display(), err))
                 .and_then(move |connection| {
                     let state = state.clone();
                     tokio::spawn(run_connection(state, connection))
@@ -157,6 +544,23 @@ fn run_connect_socket(sockaddr: SocketAddr, peer: Peer, state: GlobalState)
     tokio::spawn(server)
 }
 
+/// events processed by a connection
+enum Event<I> {
+    Inbound(I),
+    /// time to check for the requests that timed out
+    Tick,
+    /// time to send our known peers to the remote peer
+    Gossip,
+    /// time to announce the new transactions to the remote peer
+    Relay,
+}
+
+/// messages to send to the peer
+enum Outbound {
+    Message(Message),
+    Request(PeerMsg),
+    Data(DataMessage),
+}
 
 fn run_connection<T>(state: ConnectionState, connection: Connection<T>)
     -> impl future::Future<Item = (), Error = ()>
@@ -165,33 +569,251 @@ fn run_connection<T>(state: ConnectionState, connection: Connection<T>)
     let (sink, stream) = connection.split();
 
     let (sink_tx, sink_rx) = mpsc::unbounded();
+    let (data_tx, data_rx) = mpsc::unbounded();
+
+    let (peer_id, requests) = state.peers.register(state.remote(), state.direction);
+    state.relay.register(peer_id);
+    let pending = Arc::new(Mutex::new(PendingRequests::new(state.timeout)));
 
-    let stream = stream.for_each(move |inbound| {
-        debug!("[{}] inbound: {:?}", state.connection, inbound);
-        match inbound {
-            Inbound::NewNode(lwcid, node_id) => {
-                sink_tx.unbounded_send(Message::AckNodeId(lwcid, node_id)).unwrap();
+    let ticks = Interval::new(Instant::now() + TIMEOUT_CHECK_INTERVAL, TIMEOUT_CHECK_INTERVAL)
+        .map(|_| Event::Tick)
+        .map_err(|err| error!("connection timer error {}", err));
+    let gossips = Interval::new(Instant::now(), GOSSIP_INTERVAL)
+        .map(|_| Event::Gossip)
+        .map_err(|err| error!("connection timer error {}", err));
+    let relays = Interval::new(Instant::now() + RELAY_INTERVAL, RELAY_INTERVAL)
+        .map(|_| Event::Relay)
+        .map_err(|err| error!("connection timer error {}", err));
+
+    let stream_pending = pending.clone();
+    let stream_state = state.clone();
+    let stream = stream.map(Event::Inbound).map_err(|err| {
+        error!("connection stream error {:#?}", err)
+    }).select(ticks).select(gossips).select(relays).for_each(move |event| {
+        let state = &stream_state;
+        let mut pending = stream_pending.lock().unwrap();
+        // a block the peer sent without being asked, e.g. a new block
+        let mut unsolicited = None;
+        match event {
+            Event::Tick => {
+                let expired = pending.expire(Instant::now());
+                if expired > 0 {
+                    // the peer is not responding, there is no need to
+                    // keep the connection open
+                    warn!("[{}] {} request(s) timed out after {:?}, disconnecting",
+                          state.connection, expired, state.timeout);
+                    state.reputation.write().unwrap().report(&state.remote(), Offence::Timeout);
+                    return future::Either::A(future::err(()));
+                }
+                if let Some(addr) = state.remote_socket() {
+                    if state.reputation.read().unwrap().is_banned(&addr.ip()) {
+                        info!("[{}] disconnecting banned peer {}", state.connection, addr);
+                        return future::Either::A(future::err(()));
+                    }
+                }
+            },
+            Event::Gossip => {
+                data_tx.unbounded_send(Outbound::Data(DataMessage::Gossip(state.gossip()))).unwrap();
+            },
+            Event::Relay => {
+                let ids = state.relay.next_batch(peer_id);
+                if !ids.is_empty() {
+                    data_tx.unbounded_send(Outbound::Data(DataMessage::ProposeTransactions(ids))).unwrap();
+                }
+            },
+            Event::Inbound(inbound) => {
+                debug!("[{}] inbound: {:?}", state.connection, inbound);
+                match inbound {
+                    Inbound::NewNode(lwcid, node_id) => {
+                        sink_tx.unbounded_send(Message::AckNodeId(lwcid, node_id)).unwrap();
+                    },
+                    Inbound::BlockHeaders(lwcid, response) => {
+                        pending.block_headers(lwcid, response);
+                    },
+                    Inbound::Block(lwcid, response) => {
+                        unsolicited = pending.block(lwcid, response);
+                    },
+                    Inbound::CloseConnection(lwcid) => {
+                        pending.close(lwcid);
+                    },
+                    Inbound::Data(_lwcid, bytes) => {
+                        handle_data(state, peer_id, &data_tx, &bytes);
+                    },
+                    Inbound::GetBlockHeaders(lwcid, request) => {
+                        let reply = Box::new(QueryReply::new(lwcid, sink_tx.clone()));
+                        let msg = match request.to {
+                            // without `to` the peer asks for our tip
+                            None => ClientMsg::GetBlockTip(reply),
+                            Some(to) => ClientMsg::GetBlockHeaders(request.from, to, reply),
+                        };
+                        query::submit(state, peer_id, msg);
+                    },
+                    Inbound::GetBlocks(lwcid, request) => {
+                        let reply = QueryReply::new(lwcid, sink_tx.clone());
+                        query::submit(state, peer_id, ClientMsg::GetBlocks(request.from, request.to, Box::new(reply)));
+                    },
+                    _inbound => {
+                    }
+                }
+            },
+        }
+        match unsolicited {
+            None => future::Either::A(future::ok(())),
+            // the block mailbox is bounded: the next message of the peer
+            // is only read once the block task has room for this one
+            Some(block) => {
+                let msg = BlockMsg::NetworkBlock(peer_id, state.remote(), block);
+                future::Either::B(state.channels.block_box.clone().send(msg))
             },
-            inbound => {
-            }
         }
-        future::ok(())
-    }).map_err(|err| {
-        error!("connection stream error {:#?}", err)
     });
 
-    let sink = sink_rx.fold(sink, |sink, outbound| {
+    let sink_pending = pending.clone();
+    let outbound = sink_rx.map(Outbound::Message)
+        .select(requests.map(Outbound::Request))
+        .select(data_rx);
+    let sink = outbound.fold(sink, move |sink, outbound| {
         // debug!("[{}] outbound: {:?}", state.connection, outbound);
         match outbound {
-            Message::AckNodeId(_lwcid, node_id) => {
+            Outbound::Message(Message::AckNodeId(_lwcid, node_id)) => {
                 future::Either::A(sink.ack_node_id(node_id)
                     .map_err(|err| error!("err {:?}", err)))
             },
-            message => future::Either::B(sink.send(message)
-                    .map_err(|err| error!("err {:?}", err)))
+            Outbound::Message(message) => future::Either::B(future::Either::A(sink.send(message)
+                    .map_err(|err| error!("err {:?}", err)))),
+            Outbound::Data(data) => {
+                // the data messages are sent on their own light weight
+                // connection, closed right after
+                future::Either::B(future::Either::B(future::Either::A(sink.new_light_connection()
+                    .and_then(move |(lwcid, sink)| {
+                        sink.send(Message::Bytes(lwcid, data.to_bytes().into()))
+                            .and_then(move |sink| sink.send(Message::CloseConnection(lwcid)))
+                    })
+                    .map_err(|err| error!("err {:?}", err)))))
+            },
+            Outbound::Request(request) => {
+                // every request is sent on its own light weight
+                // connection so we can match the response
+                let pending = sink_pending.clone();
+                future::Either::B(future::Either::B(future::Either::B(sink.new_light_connection()
+                    .map_err(|err| error!("err {:?}", err))
+                    .and_then(move |(lwcid, sink)| {
+                        let message = pending.lock().unwrap().insert(lwcid, request);
+                        sink.send(message).map_err(|err| error!("err {:?}", err))
+                    }))))
+            },
         }
     }).map(|_| ());
 
+    let peers = state.peers.clone();
+    let relay = state.relay.clone();
     stream.select(sink)
-        .then(|_| { info!("closing connection"); Ok(()) })
+        .then(move |_| {
+            info!("closing connection");
+            peers.unregister(peer_id);
+            relay.unregister(peer_id);
+            pending.lock().unwrap().close_all();
+            Ok(())
+        })
+}
+
+/// handle the data messages of the remote peer: the gossip and the
+/// transaction relay
+fn handle_data(
+    state: &ConnectionState,
+    peer: PeerId,
+    data_tx: &mpsc::UnboundedSender<Outbound>,
+    bytes: &[u8],
+) {
+    let message = match DataMessage::from_bytes(bytes) {
+        Ok(message) => message,
+        Err(err) => {
+            warn!("[{}] invalid data message: {}", state.connection, err);
+            state.reputation.write().unwrap().report(&state.remote(), Offence::InvalidMessage);
+            return;
+        },
+    };
+    // the peer does not follow the rate limit of the relay
+    let flooding = match message {
+        DataMessage::Gossip(_) => false,
+        DataMessage::ProposeTransactions(ref ids) => ids.len() > ANNOUNCE_BATCH,
+        DataMessage::ProposeTransactionsResponse(ref items) => items.len() > ANNOUNCE_BATCH,
+        DataMessage::Transactions(ref transactions) => transactions.len() > ANNOUNCE_BATCH,
+    };
+    if flooding {
+        warn!("[{}] too many transactions relayed at once", state.connection);
+        state.reputation.write().unwrap().report(&state.remote(), Offence::InvalidMessage);
+        return;
+    }
+
+    match message {
+        DataMessage::Gossip(gossip) => handle_gossip(state, gossip),
+        DataMessage::ProposeTransactions(ids) => {
+            state.relay.known(peer, &ids);
+            let (reply, statuses) = reply_channel();
+            let request = TransactionMsg::ProposeTransactions(ids.clone(), Box::new(reply));
+            if state.channels.transaction_box.clone().try_send(request).is_err() {
+                debug!("[{}] transaction task busy, ignoring the proposed transactions", state.connection);
+                return;
+            }
+            let data_tx = data_tx.clone();
+            tokio::spawn(statuses
+                .map(move |statuses| {
+                    let items = ids.into_iter().zip(statuses).collect();
+                    let response = DataMessage::ProposeTransactionsResponse(items);
+                    let _ = data_tx.unbounded_send(Outbound::Data(response));
+                })
+                .map_err(|err| debug!("cannot answer the proposed transactions: {}", err)));
+        },
+        DataMessage::ProposeTransactionsResponse(items) => {
+            // only send the transactions the peer does not have
+            let new = items.into_iter()
+                .filter(|(_, status)| *status == ProposeStatus::New)
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>();
+            if new.is_empty() {
+                return;
+            }
+            let (reply, transactions) = reply_channel();
+            let request = TransactionMsg::GetTransactions(new, Box::new(reply));
+            if state.channels.transaction_box.clone().try_send(request).is_err() {
+                debug!("[{}] transaction task busy, not sending the transactions", state.connection);
+                return;
+            }
+            let data_tx = data_tx.clone();
+            tokio::spawn(transactions
+                .map(move |transactions: Vec<_>| {
+                    if !transactions.is_empty() {
+                        let message = DataMessage::Transactions(transactions);
+                        let _ = data_tx.unbounded_send(Outbound::Data(message));
+                    }
+                })
+                .map_err(|err| debug!("cannot send the proposed transactions: {}", err)));
+        },
+        DataMessage::Transactions(transactions) => {
+            let ids = transactions.iter().map(|tx| tx.tx.id()).collect::<Vec<_>>();
+            state.relay.known(peer, &ids);
+            let request = TransactionMsg::SendTransactions(transactions, Some(peer));
+            if state.channels.transaction_box.clone().try_send(request).is_err() {
+                debug!("[{}] transaction task busy, dropping {} transactions", state.connection, ids.len());
+            }
+        },
+    }
+}
+
+/// record the addresses gossiped by the remote peer
+fn handle_gossip(state: &ConnectionState, gossip: Gossip) {
+    let remote = state.remote_socket();
+    let addresses = gossip.addresses(remote);
+    debug!("[{}] received {} peer addresses", state.connection, addresses.len());
+    // the peers connected by unix domain sockets are local tools, they
+    // do not advertise peers
+    let source = match remote {
+        None => return,
+        Some(remote) => remote.ip(),
+    };
+    let mut peer_table = state.peer_table.write().unwrap();
+    for address in addresses {
+        peer_table.learn(address, source);
+    }
 }
@@ -0,0 +1,114 @@
+//! the peers our node is currently connected to
+//!
+//! Every connection registers itself here so the other modules can
+//! send requests to the peers (get the tip, download headers or
+//! blocks...) without knowing about the connections themselves.
+//!
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, RwLock, atomic::{AtomicUsize, Ordering}},
+};
+
+use futures::sync::mpsc;
+use intercom::PeerMsg;
+use settings::network;
+
+/// identifier of a connection, unique for the lifetime of the node
+pub type PeerId = usize;
+
+/// which side initiated the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// the remote peer connected to one of our listeners
+    Inbound,
+    /// we connected to the remote peer
+    Outbound,
+}
+
+/// handle to send requests to a connected peer
+#[derive(Clone)]
+pub struct PeerHandle {
+    pub id: PeerId,
+    pub connection: network::Connection,
+    pub direction: Direction,
+    requests: mpsc::UnboundedSender<PeerMsg>,
+}
+
+impl PeerHandle {
+    /// send a request to the peer, the request is given back if the
+    /// connection is closed already.
+    pub fn send(&self, request: PeerMsg) -> Result<(), PeerMsg> {
+        self.requests
+            .unbounded_send(request)
+            .map_err(|err| err.into_inner())
+    }
+}
+
+#[derive(Clone)]
+pub struct ConnectedPeers {
+    next_id: Arc<AtomicUsize>,
+    peers: Arc<RwLock<HashMap<PeerId, PeerHandle>>>,
+}
+
+impl ConnectedPeers {
+    pub fn new() -> Self {
+        ConnectedPeers {
+            next_id: Arc::new(AtomicUsize::new(0)),
+            peers: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// register a new connection, returns the identifier of the peer
+    /// and the stream of requests the connection needs to send to the
+    /// remote peer.
+    pub fn register(
+        &self,
+        connection: network::Connection,
+        direction: Direction,
+    ) -> (PeerId, mpsc::UnboundedReceiver<PeerMsg>) {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = mpsc::unbounded();
+        let handle = PeerHandle {
+            id: id,
+            connection: connection,
+            direction: direction,
+            requests: tx,
+        };
+        self.peers.write().unwrap().insert(id, handle);
+        (id, rx)
+    }
+
+    pub fn unregister(&self, id: PeerId) {
+        self.peers.write().unwrap().remove(&id);
+    }
+
+    /// list the handles of all the connected peers
+    pub fn handles(&self) -> Vec<PeerHandle> {
+        self.peers.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn get(&self, id: PeerId) -> Option<PeerHandle> {
+        self.peers.read().unwrap().get(&id).cloned()
+    }
+
+    /// the remote addresses of the peers we connected to
+    pub fn outbound_addresses(&self) -> Vec<SocketAddr> {
+        self.peers
+            .read()
+            .unwrap()
+            .values()
+            .filter(|handle| handle.direction == Direction::Outbound)
+            .filter_map(|handle| match handle.connection {
+                network::Connection::Socket(addr) => Some(addr),
+                #[cfg(unix)]
+                network::Connection::Unix(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.read().unwrap().len()
+    }
+}
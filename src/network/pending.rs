@@ -0,0 +1,169 @@
+//! the requests sent to a peer that are still waiting for a response
+//!
+//! Every request is sent on its own light weight connection and is
+//! given a deadline. A request that does not complete before its
+//! deadline is answered with a timeout error, so the handles never
+//! wait indefinitely on a peer that stopped responding.
+//!
+
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use blockcfg::{Block, Header};
+use intercom::{BoxReply, BoxStreamReply, Error, ErrorKind, PeerMsg};
+use protocol::{
+    protocol::{BlockHeaders, GetBlockHeaders, GetBlocks, LightWeightConnectionId, Response},
+    Message,
+};
+
+enum PendingReply {
+    BlockTip(BoxReply<Header>),
+    BlockHeaders(BoxReply<Vec<Header>>),
+    Blocks(BoxStreamReply<Block>),
+}
+
+impl PendingReply {
+    fn reply_error(self, error: Error) {
+        match self {
+            PendingReply::BlockTip(mut reply) => reply.reply_error(error),
+            PendingReply::BlockHeaders(mut reply) => reply.reply_error(error),
+            PendingReply::Blocks(mut reply) => {
+                reply.send_error(error);
+                reply.close();
+            }
+        }
+    }
+}
+
+pub struct PendingRequests {
+    timeout: Duration,
+    requests: HashMap<LightWeightConnectionId, (Instant, PendingReply)>,
+}
+
+fn timeout_error(timeout: Duration) -> Error {
+    Error::new(
+        ErrorKind::DeadlineExceeded,
+        format!("peer did not respond within {:?}", timeout),
+    )
+}
+
+impl PendingRequests {
+    pub fn new(timeout: Duration) -> Self {
+        PendingRequests {
+            timeout: timeout,
+            requests: HashMap::new(),
+        }
+    }
+
+    /// register the request and returns the message to send to the
+    /// peer on the given light weight connection.
+    pub fn insert(&mut self, lwcid: LightWeightConnectionId, request: PeerMsg) -> Message {
+        let deadline = Instant::now() + self.timeout;
+        let (reply, message) = match request {
+            PeerMsg::GetBlockTip(reply) => (
+                PendingReply::BlockTip(reply),
+                Message::GetBlockHeaders(lwcid, GetBlockHeaders { from: vec![], to: None }),
+            ),
+            PeerMsg::GetBlockHeaders(from, to, reply) => (
+                PendingReply::BlockHeaders(reply),
+                Message::GetBlockHeaders(lwcid, GetBlockHeaders { from: from, to: Some(to) }),
+            ),
+            PeerMsg::GetBlocks(from, to, reply) => (
+                PendingReply::Blocks(reply),
+                Message::GetBlocks(lwcid, GetBlocks { from: from, to: to }),
+            ),
+        };
+        self.requests.insert(lwcid, (deadline, reply));
+        message
+    }
+
+    /// the peer answered a `GetBlockHeaders` request
+    pub fn block_headers(
+        &mut self,
+        lwcid: LightWeightConnectionId,
+        response: Response<BlockHeaders, String>,
+    ) {
+        let result = match response {
+            Response::Ok(headers) => Ok(headers.0),
+            Response::Err(err) => Err(Error::from(err)),
+        };
+        match self.requests.remove(&lwcid) {
+            Some((_, PendingReply::BlockTip(mut reply))) => {
+                reply.reply(result.and_then(|mut headers| {
+                    headers
+                        .pop()
+                        .ok_or_else(|| Error::from("peer did not send its tip".to_owned()))
+                }));
+            }
+            Some((_, PendingReply::BlockHeaders(mut reply))) => reply.reply(result),
+            Some((deadline, other)) => {
+                warn!("unexpected block headers on light connection {:?}", lwcid);
+                self.requests.insert(lwcid, (deadline, other));
+            }
+            None => warn!("block headers received for unknown request {:?}", lwcid),
+        }
+    }
+
+    /// the peer sent one of the blocks of a `GetBlocks` request, the
+    /// deadline is extended as the peer is still responding. A block
+    /// sent outside of a request is given back.
+    pub fn block(&mut self, lwcid: LightWeightConnectionId, response: Response<Block, String>) -> Option<Block> {
+        let timeout = self.timeout;
+        match self.requests.get_mut(&lwcid) {
+            Some((deadline, PendingReply::Blocks(reply))) => {
+                *deadline = Instant::now() + timeout;
+                match response {
+                    Response::Ok(block) => reply.send(block),
+                    Response::Err(err) => reply.send_error(Error::from(err)),
+                }
+                None
+            }
+            Some(_) => {
+                warn!("block received for another request {:?}", lwcid);
+                None
+            }
+            None => match response {
+                Response::Ok(block) => Some(block),
+                Response::Err(err) => {
+                    debug!("error received for unknown request {:?}: {}", lwcid, err);
+                    None
+                }
+            },
+        }
+    }
+
+    /// the peer closed the light weight connection of a request
+    pub fn close(&mut self, lwcid: LightWeightConnectionId) {
+        match self.requests.remove(&lwcid) {
+            Some((_, PendingReply::Blocks(mut reply))) => reply.close(),
+            Some((_, reply)) => {
+                reply.reply_error(Error::new(ErrorKind::Unavailable, "peer closed the request"))
+            }
+            None => {}
+        }
+    }
+
+    /// answer all the requests whose deadline has passed with a
+    /// timeout error. Returns the number of requests that expired.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let expired = self
+            .requests
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(lwcid, _)| *lwcid)
+            .collect::<Vec<_>>();
+        for lwcid in expired.iter() {
+            if let Some((_, reply)) = self.requests.remove(lwcid) {
+                reply.reply_error(timeout_error(self.timeout));
+            }
+        }
+        expired.len()
+    }
+
+    /// the connection is closing: none of the pending requests will
+    /// complete.
+    pub fn close_all(&mut self) {
+        for (_, (_, reply)) in self.requests.drain() {
+            reply.reply_error(Error::new(ErrorKind::Unavailable, "connection closed"));
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! the queries of the remote peers
+//!
+//! The requests of the peer for our tip, headers and blocks are served
+//! by the client task, which limits the resources each peer can use. The replies are
+//! sent back on the light weight connection of the request, closed
+//! once the reply is complete.
+//!
+
+use std::sync::mpsc::TrySendError;
+
+use blockcfg::{Header, RawBlock};
+use cbor_event::{se::Serializer, Len};
+use futures::sync::mpsc;
+use intercom::{ClientMsg, ClientQuery, Error, ErrorKind, Reply, StreamReply};
+use protocol::{
+    protocol::{BlockHeaders, LightWeightConnectionId, Response},
+    Message,
+};
+
+use super::{ConnectionState, PeerId};
+
+/// answers a query of the remote peer on the light weight connection
+/// of the query
+#[derive(Debug)]
+pub struct QueryReply {
+    lwcid: LightWeightConnectionId,
+    sink: mpsc::UnboundedSender<Message>,
+    /// the connection is closed, there is no one to reply to
+    closed: bool,
+}
+
+impl QueryReply {
+    pub fn new(lwcid: LightWeightConnectionId, sink: mpsc::UnboundedSender<Message>) -> Self {
+        QueryReply {
+            lwcid: lwcid,
+            sink: sink,
+            closed: false,
+        }
+    }
+
+    fn send_message(&mut self, message: Message) {
+        if self.sink.unbounded_send(message).is_err() {
+            self.closed = true;
+        }
+    }
+
+    fn close_connection(&mut self) {
+        let message = Message::CloseConnection(self.lwcid);
+        self.send_message(message)
+    }
+}
+
+/// the tip is requested with an empty `GetBlockHeaders`
+impl Reply<Header> for QueryReply {
+    fn reply_ok(&mut self, header: Header) {
+        Reply::<Vec<Header>>::reply_ok(self, vec![header])
+    }
+    fn reply_error(&mut self, error: Error) {
+        Reply::<Vec<Header>>::reply_error(self, error)
+    }
+}
+
+impl Reply<Vec<Header>> for QueryReply {
+    fn reply_ok(&mut self, headers: Vec<Header>) {
+        let message = Message::BlockHeaders(self.lwcid, Response::Ok(BlockHeaders(headers)));
+        self.send_message(message);
+        self.close_connection()
+    }
+    fn reply_error(&mut self, error: Error) {
+        let message = Message::BlockHeaders(self.lwcid, Response::Err(error.to_string()));
+        self.send_message(message);
+        self.close_connection()
+    }
+}
+
+/// the bytes of the `Block` message answering with the given block:
+/// the encoding of `Response::Ok` around the block as stored, which is
+/// already the encoding of the block.
+pub fn block_response(rblk: &RawBlock) -> Vec<u8> {
+    let mut serializer = Serializer::new_vec();
+    serializer
+        .write_array(Len::Len(2))
+        .and_then(|s| s.write_unsigned_integer(0))
+        .and_then(|s| s.write_raw_bytes(rblk.as_ref()))
+        .expect("serialization in memory cannot fail");
+    serializer.finalize()
+}
+
+impl StreamReply<RawBlock> for QueryReply {
+    fn send(&mut self, rblk: RawBlock) {
+        // the block is sent as stored, without decoding it
+        let message = Message::Bytes(self.lwcid, block_response(&rblk).into());
+        self.send_message(message)
+    }
+    fn send_error(&mut self, error: Error) {
+        let message = Message::Block(self.lwcid, Response::Err(error.to_string()));
+        self.send_message(message)
+    }
+    fn close(&mut self) {
+        self.close_connection()
+    }
+    fn is_cancelled(&self) -> bool {
+        self.closed
+    }
+}
+
+/// give the query of the peer to the client task, the query is
+/// answered with an error if the task is too busy to take it.
+pub fn submit(state: &ConnectionState, peer: PeerId, msg: ClientMsg) {
+    let query = ClientQuery { peer: peer, msg: msg };
+    let query = match state.channels.client_box.clone().try_send(query) {
+        Ok(()) => return,
+        Err(TrySendError::Full(query)) | Err(TrySendError::Disconnected(query)) => query,
+    };
+    debug!("[{}] client task busy, refusing the query of the peer", state.connection);
+    query.msg.reply_error(Error::new(ErrorKind::Busy, "the node is too busy to serve the query"));
+}
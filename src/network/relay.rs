@@ -0,0 +1,121 @@
+//! transaction relay
+//!
+//! The transactions are relayed by inventory: the nodes announce the
+//! ids of the new transactions of their pool to their peers, the peers
+//! answer which ones are new to them (`ProposeStatus`) and only these
+//! are sent. A transaction is not announced to a peer that sent it to
+//! us or announced it already.
+//!
+//! The relay is rate limited: each connection announces at most
+//! `ANNOUNCE_BATCH` ids every `RELAY_INTERVAL`, the other ones wait in
+//! the queue of the peer. When the queue is full the oldest ids are
+//! dropped: the peers get them from the other nodes.
+//!
+
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use blockcfg::TransactionId;
+
+use super::peers::PeerId;
+
+/// how often the connections announce the queued transactions
+pub const RELAY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// maximum number of transaction ids announced at once, the peers
+/// announcing more are misbehaving
+pub const ANNOUNCE_BATCH: usize = 100;
+
+/// maximum number of transaction ids waiting to be announced to a peer
+const MAX_QUEUE: usize = 10_000;
+
+/// number of transaction ids remembered as known by a peer
+const MAX_KNOWN: usize = 10_000;
+
+/// the transactions known by a peer, the oldest are forgotten first
+#[derive(Default)]
+struct Known {
+    ids: BTreeSet<TransactionId>,
+    order: VecDeque<TransactionId>,
+}
+
+impl Known {
+    fn contains(&self, id: &TransactionId) -> bool {
+        self.ids.contains(id)
+    }
+
+    fn insert(&mut self, id: TransactionId) {
+        if self.ids.insert(id.clone()) {
+            self.order.push_back(id);
+            if self.order.len() > MAX_KNOWN {
+                let oldest = self.order.pop_front().unwrap();
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct PeerQueue {
+    pending: VecDeque<TransactionId>,
+    known: Known,
+}
+
+#[derive(Clone, Default)]
+pub struct TransactionRelay {
+    peers: Arc<Mutex<HashMap<PeerId, PeerQueue>>>,
+}
+
+impl TransactionRelay {
+    pub fn new() -> Self {
+        TransactionRelay::default()
+    }
+
+    pub(super) fn register(&self, peer: PeerId) {
+        self.peers.lock().unwrap().insert(peer, PeerQueue::default());
+    }
+
+    pub(super) fn unregister(&self, peer: PeerId) {
+        self.peers.lock().unwrap().remove(&peer);
+    }
+
+    /// announce a new transaction of our pool to the peers, except the
+    /// peer it comes from
+    pub fn announce(&self, id: &TransactionId, from: Option<PeerId>) {
+        let mut peers = self.peers.lock().unwrap();
+        for (peer, queue) in peers.iter_mut() {
+            if Some(*peer) == from || queue.known.contains(id) {
+                continue;
+            }
+            queue.known.insert(id.clone());
+            queue.pending.push_back(id.clone());
+            if queue.pending.len() > MAX_QUEUE {
+                queue.pending.pop_front();
+            }
+        }
+    }
+
+    /// the peer has these transactions, there is no need to announce
+    /// them to it
+    pub(super) fn known(&self, peer: PeerId, ids: &[TransactionId]) {
+        if let Some(queue) = self.peers.lock().unwrap().get_mut(&peer) {
+            for id in ids {
+                queue.known.insert(id.clone());
+            }
+        }
+    }
+
+    /// the next transactions to announce to the peer
+    pub(super) fn next_batch(&self, peer: PeerId) -> Vec<TransactionId> {
+        match self.peers.lock().unwrap().get_mut(&peer) {
+            None => Vec::new(),
+            Some(queue) => {
+                let count = ::std::cmp::min(queue.pending.len(), ANNOUNCE_BATCH);
+                queue.pending.drain(..count).collect()
+            }
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! helpers to manage the unix domain socket files the node
+//! listens to.
+//!
+//! Unlike a TCP port, a unix socket leaves a file behind on the
+//! filesystem when the process that created it terminates without
+//! removing it. Binding to the same path again will then fail, so
+//! we need to detect these stale socket files and remove them before
+//! listening.
+//!
+
+use std::{
+    fs, io,
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::UnixStream,
+    },
+    path::Path,
+};
+
+/// the default permissions of the socket file: only the owner and the
+/// group of the node's process are allowed to connect to the node.
+pub const DEFAULT_SOCKET_PERMISSIONS: u32 = 0o660;
+
+/// prepare the given path so we can bind a unix listener on it.
+///
+/// * if nothing exists at the given path, there is nothing to do;
+/// * if a socket file exists but nobody is listening to it anymore,
+///   the file is a left over of a previous run and is removed;
+/// * if a socket file exists and another process is still accepting
+///   connections on it, or if the path is not a socket, the function
+///   returns an error: we don't want to steal or delete somebody
+///   else's file.
+///
+pub fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a unix socket", path.display()),
+        ));
+    }
+
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is already in use by another process", path.display()),
+        )),
+        Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+            warn!("removing stale unix socket file {}", path.display());
+            fs::remove_file(path)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// set the permissions of the socket file (the listener needs to be
+/// bound already). Only the processes allowed to write on the socket
+/// file are allowed to connect.
+pub fn set_socket_permissions(path: &Path, mode: u32) -> io::Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
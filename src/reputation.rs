@@ -0,0 +1,247 @@
+//! reputation of the peers
+//!
+//! The other tasks report here the offences committed by the peers
+//! (invalid blocks, flood of unconnected blocks, invalid transactions,
+//! unanswered requests...). Each offence adds a penalty to the score of
+//! the peer, and the score decreases back over time. A peer whose score
+//! crosses the threshold is banned for a while: the network refuses its
+//! connections and does not dial it.
+//!
+//! The bans are saved to a file so they survive a restart of the node.
+//! The file is the interface with the operator: the `bans` command
+//! lists and clears the bans, and the node reloads the file when it is
+//! modified.
+//!
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_yaml;
+use settings::network::Connection;
+
+/// the score at which a peer is banned
+const BAN_THRESHOLD: u32 = 100;
+
+/// how long a peer remains banned
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// the score of a peer decreases by one point every period
+const SCORE_DECAY_PERIOD: Duration = Duration::from_secs(60);
+
+/// the offences the peers can be reported for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offence {
+    /// the peer sent a block that failed the verification
+    InvalidBlock,
+    /// the peer sent a block whose parent we don't know
+    UnconnectedBlock,
+    /// the peer sent a transaction that failed the verification
+    InvalidTransaction,
+    /// the peer did not answer a request in time
+    Timeout,
+    /// the peer sent a message we could not decode
+    InvalidMessage,
+}
+
+impl Offence {
+    fn penalty(self) -> u32 {
+        match self {
+            Offence::InvalidBlock => 50,
+            Offence::UnconnectedBlock => 2,
+            Offence::InvalidTransaction => 10,
+            Offence::Timeout => 20,
+            Offence::InvalidMessage => 25,
+        }
+    }
+}
+
+impl fmt::Display for Offence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Offence::InvalidBlock => write!(f, "invalid block"),
+            Offence::UnconnectedBlock => write!(f, "unconnected block"),
+            Offence::InvalidTransaction => write!(f, "invalid transaction"),
+            Offence::Timeout => write!(f, "timeout"),
+            Offence::InvalidMessage => write!(f, "invalid message"),
+        }
+    }
+}
+
+/// a banned peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub address: IpAddr,
+    /// end of the ban, in seconds since the UNIX epoch
+    pub until: u64,
+    /// the last offence of the peer
+    pub reason: String,
+}
+
+struct Score {
+    value: u32,
+    updated: SystemTime,
+}
+
+impl Score {
+    /// the score after the decay since the last update
+    fn current(&self, now: SystemTime) -> u32 {
+        let elapsed = now.duration_since(self.updated).unwrap_or(Duration::from_secs(0));
+        let decay = elapsed.as_secs() / SCORE_DECAY_PERIOD.as_secs();
+        self.value.saturating_sub(decay as u32)
+    }
+}
+
+pub struct Reputation {
+    scores: HashMap<IpAddr, Score>,
+    bans: BTreeMap<IpAddr, Ban>,
+    ban_duration: Duration,
+    /// where the bans are saved
+    path: Option<PathBuf>,
+    /// modification time of the file when we last read or wrote it
+    loaded: Option<SystemTime>,
+}
+
+pub type ReputationR = Arc<RwLock<Reputation>>;
+
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// read the bans from the given file, an absent file means no bans
+pub fn read_bans(path: &Path) -> io::Result<Vec<Ban>> {
+    match fs::File::open(path) {
+        Ok(file) => serde_yaml::from_reader(file)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+/// write the bans in the given file
+pub fn write_bans(path: &Path, bans: &[Ban]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let file = fs::File::create(&tmp)?;
+        serde_yaml::to_writer(file, &bans)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    }
+    fs::rename(tmp, path)
+}
+
+impl Reputation {
+    pub fn new(ban_duration: Duration) -> Self {
+        Reputation {
+            scores: HashMap::new(),
+            bans: BTreeMap::new(),
+            ban_duration: ban_duration,
+            path: None,
+            loaded: None,
+        }
+    }
+
+    /// create the reputation, with the bans saved in the given file
+    pub fn load(path: PathBuf, ban_duration: Duration) -> io::Result<Self> {
+        let mut reputation = Reputation::new(ban_duration);
+        reputation.path = Some(path);
+        reputation.reload()?;
+        Ok(reputation)
+    }
+
+    fn reload(&mut self) -> io::Result<()> {
+        if let Some(ref path) = self.path {
+            self.loaded = modified(path);
+            self.bans = read_bans(path)?
+                .into_iter()
+                .map(|ban| (ban.address, ban))
+                .collect();
+        }
+        Ok(())
+    }
+
+    fn save(&mut self) {
+        // the expired bans are only dropped here, `is_banned` ignores them
+        let now = seconds_since_epoch(SystemTime::now());
+        self.bans.retain(|_, ban| ban.until > now);
+        if let Some(path) = self.path.clone() {
+            let bans = self.bans.values().cloned().collect::<Vec<_>>();
+            match write_bans(&path, &bans) {
+                Ok(()) => self.loaded = modified(&path),
+                Err(err) => error!("cannot save the bans to {}: {}", path.display(), err),
+            }
+        }
+    }
+
+    /// reload the bans if the file was modified by the operator
+    pub fn refresh(&mut self) {
+        let changed = match self.path {
+            Some(ref path) => modified(path) != self.loaded,
+            None => false,
+        };
+        if changed {
+            info!("reloading the banned peers");
+            if let Err(err) = self.reload() {
+                error!("cannot reload the banned peers: {}", err)
+            }
+        }
+    }
+
+    /// report an offence of the given peer. Returns true if the peer
+    /// is banned as a consequence.
+    pub fn report(&mut self, peer: &Connection, offence: Offence) -> bool {
+        let address = match peer {
+            Connection::Socket(addr) => addr.ip(),
+            // local peers are trusted
+            #[cfg(unix)]
+            Connection::Unix(_) => return false,
+        };
+
+        let now = SystemTime::now();
+        let value = {
+            let score = self.scores.entry(address).or_insert(Score { value: 0, updated: now });
+            score.value = score.current(now) + offence.penalty();
+            score.updated = now;
+            score.value
+        };
+        debug!("peer {} reported for {}, score {}", address, offence, value);
+
+        if value < BAN_THRESHOLD {
+            return false;
+        }
+
+        warn!("banning peer {} for {:?} ({})", address, self.ban_duration, offence);
+        self.scores.remove(&address);
+        self.bans.insert(
+            address,
+            Ban {
+                address: address,
+                until: seconds_since_epoch(now + self.ban_duration),
+                reason: offence.to_string(),
+            },
+        );
+        self.save();
+        true
+    }
+
+    /// check if the given IP address is banned. The expired bans are
+    /// ignored, they are removed with the next save of the file.
+    pub fn is_banned(&self, address: &IpAddr) -> bool {
+        match self.bans.get(address) {
+            None => false,
+            Some(ban) => ban.until > seconds_since_epoch(SystemTime::now()),
+        }
+    }
+
+    pub fn bans(&self) -> Vec<Ban> {
+        self.bans.values().cloned().collect()
+    }
+}
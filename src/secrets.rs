@@ -0,0 +1,210 @@
+//! the secrets of the node
+//!
+//! A leader node signs the blocks of its slots with its secret key,
+//! given with `--secret`: a file holding the extended private key in
+//! hexadecimal (see `xchain keygen`). The file must only be readable by
+//! its owner.
+//!
+//! The public key of the secret is matched against the leaders of the
+//! configuration, a node without a matching secret does not lead any
+//! slot. The secret key bytes are cleared from memory when the secret
+//! is dropped, as are the buffers it was read from and the extended
+//! keys built from them.
+//!
+
+use std::{
+    fmt, fs,
+    io::{self, Read, Write},
+    mem,
+    ops::Deref,
+    path::{Path, PathBuf},
+    slice,
+};
+
+use clear_on_drop::{clear::Clear, ClearOnDrop};
+use rand;
+use xblockchain::hdwallet::{Seed, Signature, XPrv, XPub, SEED_SIZE, XPRV_SIZE};
+use xblockchain::util::hex;
+
+pub struct NodeSecret {
+    xprv: ClearOnDrop<Box<[u8]>>,
+    public: XPub,
+    path: PathBuf,
+}
+
+/// a secret of the node matching a leader of the configuration
+#[derive(Debug)]
+pub struct Leader {
+    pub secret: NodeSecret,
+    /// the position of the leader in the configuration
+    pub index: usize,
+}
+
+/// a key built from the secret (e.g. an `XPrv`), its memory is cleared
+/// when it is dropped. Only for the key types made of plain bytes.
+struct ClearedKey<K>(K);
+
+impl<K> Deref for ClearedKey<K> {
+    type Target = K;
+    fn deref(&self) -> &K {
+        &self.0
+    }
+}
+
+impl<K> Drop for ClearedKey<K> {
+    fn drop(&mut self) {
+        // the key holds no pointer, its bytes are overwritten in place
+        let bytes = unsafe { slice::from_raw_parts_mut(&mut self.0 as *mut K as *mut u8, mem::size_of::<K>()) };
+        Clear::clear(bytes);
+    }
+}
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn ::std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// refuse the secret files other users can access
+#[cfg(unix)]
+fn check_permissions(path: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = metadata.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "the secret file {} is accessible by other users (mode {:o}), restrict it with `chmod 600`",
+                path.display(),
+                mode & 0o777
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path, _metadata: &fs::Metadata) -> io::Result<()> {
+    Ok(())
+}
+
+/// create a new file only readable by its owner, an existing file is
+/// not overwritten
+fn create_secret_file(path: &Path) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+impl NodeSecret {
+    pub fn from_bytes(bytes: &[u8], path: PathBuf) -> io::Result<Self> {
+        if bytes.len() != XPRV_SIZE {
+            return Err(invalid_data(format!(
+                "invalid secret key size {} (expected {})",
+                bytes.len(),
+                XPRV_SIZE
+            )));
+        }
+        let xprv = ClearOnDrop::new(bytes.to_vec().into_boxed_slice());
+        let key = XPrv::from_slice_verified(&xprv)
+            .map(ClearedKey)
+            .map_err(|err| invalid_data(format!("invalid secret key: {:?}", err)))?;
+        let public = key.public();
+        Ok(NodeSecret {
+            xprv: xprv,
+            public: public,
+            path: path,
+        })
+    }
+
+    fn from_hex(contents: &[u8], path: PathBuf) -> io::Result<Self> {
+        let text = ::std::str::from_utf8(contents).map_err(invalid_data)?;
+        let mut bytes =
+            hex::decode(text.trim()).map_err(|err| invalid_data(format!("invalid secret key: {:?}", err)))?;
+        let secret = NodeSecret::from_bytes(&bytes, path);
+        Clear::clear(&mut bytes[..]);
+        secret
+    }
+
+    /// read the secret key from the file, in hexadecimal
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut file = fs::File::open(path)?;
+        let metadata = file.metadata()?;
+        check_permissions(path, &metadata)?;
+        // allocated once so the key is not left in a reallocated buffer
+        let mut contents = Vec::with_capacity(metadata.len() as usize + 1);
+        let secret = file
+            .read_to_end(&mut contents)
+            .and_then(|_| NodeSecret::from_hex(&contents, path.to_path_buf()));
+        Clear::clear(&mut contents[..]);
+        secret
+    }
+
+    /// generate a new random secret key and write it to a new file
+    pub fn generate(path: &Path) -> io::Result<Self> {
+        let mut bytes = rand::random::<[u8; SEED_SIZE]>();
+        let seed = ClearedKey(Seed::from_bytes(bytes));
+        Clear::clear(&mut bytes[..]);
+        let xprv = ClearedKey(XPrv::generate_from_seed(&seed));
+        let secret = NodeSecret::from_bytes(xprv.as_ref(), path.to_path_buf())?;
+
+        let mut contents = hex::encode(&secret.xprv).into_bytes();
+        let written = create_secret_file(path).and_then(|mut file| {
+            file.write_all(&contents)?;
+            file.write_all(b"\n")?;
+            file.sync_all()
+        });
+        Clear::clear(&mut contents[..]);
+        written.map(|()| secret)
+    }
+
+    pub fn public(&self) -> &XPub {
+        &self.public
+    }
+
+    /// sign the data with the secret key. The extended key is built
+    /// from the secret key bytes for each signature, and cleared from
+    /// memory once the signature is made.
+    pub fn sign<T>(&self, data: &[u8]) -> Signature<T> {
+        let key = XPrv::from_slice_verified(&self.xprv)
+            .map(ClearedKey)
+            .expect("the secret key was verified on load");
+        key.sign(data)
+    }
+}
+
+impl fmt::Debug for NodeSecret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // never print the secret key
+        f.debug_struct("NodeSecret")
+            .field("public", &format!("{}", self.public))
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+/// load the secrets of the node and find the first one matching a
+/// leader of the configuration, the secrets matching no leader are
+/// dropped.
+pub fn load_leader(paths: &[PathBuf], leaders: &[XPub]) -> io::Result<Option<Leader>> {
+    for path in paths {
+        let secret = NodeSecret::load(path)
+            .map_err(|err| io::Error::new(err.kind(), format!("cannot load the secret {}: {}", path.display(), err)))?;
+        match leaders.iter().position(|leader| leader == secret.public()) {
+            Some(index) => return Ok(Some(Leader { secret: secret, index: index })),
+            None => warn!(
+                "the public key {} of the secret {} is not a leader of the configuration",
+                secret.public(),
+                path.display()
+            ),
+        }
+    }
+    Ok(None)
+}
@@ -2,6 +2,7 @@ use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use super::network::{Listen, Peer};
+use blockcfg::BlockHash;
 
 use structopt::{StructOpt};
 
@@ -20,6 +21,10 @@ pub struct CommandArguments {
     /// the address to listen inbound connections from. The network will
     /// open an listening socket to the given address. You might need to have
     /// special privileges to open the TcpSocket from this address.
+    ///
+    /// On unix, the path to a unix domain socket file can be given
+    /// instead so local tools can talk to the node without opening a
+    /// TCP port. A stale socket file left by a previous run is removed.
     #[structopt(long = "listen-from", parse(try_from_str))]
     pub listen_addr: Vec<Listen>,
 
@@ -30,6 +35,62 @@ pub struct CommandArguments {
     #[structopt(long = "connect-to", parse(try_from_str))]
     pub connect_to: Vec<Peer>,
 
+    /// list of the nodes trusted to serve the chain up to the
+    /// checkpoint. The node connects to them like to the `--connect-to`
+    /// nodes.
+    #[structopt(long = "trusted-peers", parse(try_from_str))]
+    pub trusted_peers: Vec<Peer>,
+
+    /// the hash of the genesis block (block0) of the blockchain. The
+    /// node refuses to start if the genesis configuration does not
+    /// match it.
+    #[structopt(long = "block0-hash", parse(try_from_str))]
+    pub block0_hash: Option<BlockHash>,
+
+    /// the hash of a trusted recent block. The node synchronises up to
+    /// this block without verifying the signatures and transactions of
+    /// the blocks below it. Requires `--trusted-peers`.
+    #[structopt(long = "checkpoint", parse(try_from_str))]
+    pub checkpoint: Option<BlockHash>,
+
+    /// the maximum number of inbound connections accepted at the same
+    /// time. New connections above this limit are dropped.
+    #[structopt(long = "max-inbound-connections")]
+    pub max_inbound_connections: Option<usize>,
+
+    /// the number of peers the node tries to stay connected to. The
+    /// node dials the best peers it learnt from the other nodes until
+    /// this number of outbound connections is reached.
+    #[structopt(long = "outbound-connections")]
+    pub outbound_connections: Option<usize>,
+
+    /// the file where the known peers are saved, so they can be
+    /// dialed again after a restart.
+    #[structopt(long = "peer-table", parse(from_os_str))]
+    pub peer_table: Option<PathBuf>,
+
+    /// the file where the banned peers are saved, so the bans survive
+    /// a restart of the node. Use `xchain bans` to list or clear them.
+    #[structopt(long = "bans-file", parse(from_os_str))]
+    pub bans_file: Option<PathBuf>,
+
+    /// the file of the address and transaction index, the index is
+    /// only maintained if this is set. Use `xchain index rebuild` to
+    /// build it again from the storage.
+    #[structopt(long = "index-file", parse(from_os_str))]
+    pub index_file: Option<PathBuf>,
+
+    /// the file of the secret key of the node, in hexadecimal. The node
+    /// leads the slots of the configured leader with the matching public
+    /// key. The file must only be readable by its owner.
+    #[structopt(long = "secret", parse(from_os_str))]
+    pub secret: Vec<PathBuf>,
+
+    /// the node does not lead slots until its tip is at most this
+    /// number of blocks behind the tip of the network.
+    #[structopt(long = "sync-distance")]
+    pub sync_distance: Option<usize>,
+
     /// Set the node config (in YAML format) to use as general configuration
     #[structopt(long = "config", parse(from_os_str))]
     pub node_config: PathBuf,
@@ -0,0 +1,120 @@
+mod command_arguments;
+pub mod network;
+
+use std::{fs::File, io::Read, path::PathBuf};
+
+use exe_common::genesisdata;
+use log::LevelFilter;
+use serde_yaml;
+use xblockchain::hdwallet::XPub;
+
+use self::command_arguments::CommandArguments;
+use blockcfg::{BlockHash, GenesisData};
+use bootstrap::DEFAULT_SYNC_DISTANCE;
+use network::{DEFAULT_MAX_INBOUND_CONNECTIONS, DEFAULT_OUTBOUND_CONNECTIONS};
+
+/// the node configuration file (`--config`)
+#[derive(Debug, Deserialize)]
+struct Config {
+    bft: Bft,
+}
+
+#[derive(Debug, Deserialize)]
+struct Bft {
+    /// the public keys of the leaders, in the order they lead the slots
+    leaders: Vec<XPub>,
+}
+
+/// the settings of the node, from the command line and the
+/// configuration files
+#[derive(Debug)]
+pub struct Settings {
+    pub verbose: u8,
+
+    pub network: network::Configuration,
+
+    pub block0_hash: Option<BlockHash>,
+
+    pub checkpoint: Option<BlockHash>,
+
+    pub bans_file: Option<PathBuf>,
+
+    pub index_file: Option<PathBuf>,
+
+    pub secret: Vec<PathBuf>,
+
+    pub leaders: Vec<XPub>,
+
+    pub sync_distance: usize,
+
+    pub genesis_data_config: PathBuf,
+}
+
+impl Settings {
+    /// load the settings from the command line arguments and the
+    /// node configuration file
+    ///
+    /// on error the function prints an error message and terminates
+    /// the process.
+    ///
+    pub fn load() -> Self {
+        let args = CommandArguments::load();
+
+        let config: Config = File::open(&args.node_config)
+            .map_err(|err| err.to_string())
+            .and_then(|file| serde_yaml::from_reader(file).map_err(|err| err.to_string()))
+            .unwrap_or_else(|err| {
+                eprintln!("cannot read the configuration {}: {}", args.node_config.display(), err);
+                ::std::process::exit(1)
+            });
+
+        // the blocks below the checkpoint are only downloaded from the
+        // trusted peers
+        if args.checkpoint.is_some() && args.trusted_peers.is_empty() {
+            eprintln!("--checkpoint requires at least one --trusted-peers");
+            ::std::process::exit(1)
+        }
+
+        let network = network::Configuration {
+            listen_to: args.listen_addr,
+            peer_nodes: args.connect_to,
+            trusted_peers: args.trusted_peers,
+            max_inbound_connections: args.max_inbound_connections.unwrap_or(DEFAULT_MAX_INBOUND_CONNECTIONS),
+            outbound_connections: args.outbound_connections.unwrap_or(DEFAULT_OUTBOUND_CONNECTIONS),
+            peer_table: args.peer_table,
+        };
+
+        Settings {
+            verbose: args.verbose,
+            network: network,
+            block0_hash: args.block0_hash,
+            checkpoint: args.checkpoint,
+            bans_file: args.bans_file,
+            index_file: args.index_file,
+            secret: args.secret,
+            leaders: config.bft.leaders,
+            sync_distance: args.sync_distance.unwrap_or(DEFAULT_SYNC_DISTANCE),
+            genesis_data_config: args.genesis_data_config,
+        }
+    }
+
+    pub fn get_log_level(&self) -> LevelFilter {
+        match self.verbose {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+
+    pub fn read_genesis_data(&self) -> GenesisData {
+        let mut json = String::new();
+        File::open(&self.genesis_data_config)
+            .and_then(|mut file| file.read_to_string(&mut json))
+            .unwrap_or_else(|err| {
+                eprintln!("cannot read the genesis {}: {}", self.genesis_data_config.display(), err);
+                ::std::process::exit(1)
+            });
+        genesisdata::parse::parse(json.as_bytes())
+    }
+}
@@ -0,0 +1,88 @@
+use std::{fmt, net::SocketAddr, str, time::Duration};
+#[cfg(unix)]
+use std::path::PathBuf;
+
+/// the timeout of the connections given on the command line
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// the address of a remote or local end point of the network
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Connection {
+    Socket(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Connection::Socket(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Connection::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+impl str::FromStr for Connection {
+    type Err = String;
+
+    /// a socket address, or on unix the path of a unix domain socket
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            return Ok(Connection::Socket(addr));
+        }
+        #[cfg(unix)]
+        {
+            if s.contains('/') {
+                return Ok(Connection::Unix(PathBuf::from(s)));
+            }
+        }
+        Err(format!("invalid address {}", s))
+    }
+}
+
+/// a node to connect to
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub connection: Connection,
+    pub timeout: Duration,
+}
+impl str::FromStr for Peer {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Peer { connection: s.parse()?, timeout: DEFAULT_TIMEOUT })
+    }
+}
+
+/// an address to accept the inbound connections from
+#[derive(Debug, Clone)]
+pub struct Listen {
+    pub connection: Connection,
+    pub timeout: Duration,
+}
+impl str::FromStr for Listen {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Listen { connection: s.parse()?, timeout: DEFAULT_TIMEOUT })
+    }
+}
+
+/// the network settings of the node
+#[derive(Debug, Clone)]
+pub struct Configuration {
+    /// the addresses to accept the inbound connections from
+    pub listen_to: Vec<Listen>,
+
+    /// the nodes to connect to at startup
+    pub peer_nodes: Vec<Peer>,
+
+    /// the nodes trusted to serve the chain up to the checkpoint
+    pub trusted_peers: Vec<Peer>,
+
+    /// the maximum number of inbound connections at the same time
+    pub max_inbound_connections: usize,
+
+    /// the number of outbound connections the node tries to keep
+    pub outbound_connections: usize,
+
+    /// the file where the known peers are saved
+    pub peer_table: Option<std::path::PathBuf>,
+}
@@ -1,11 +1,477 @@
-use std::sync;
+//! the ledger state of the node
+//!
+//! `State` is the part of the chain state derived from the blocks: the
+//! unspent transaction outputs, the slot leaders of the epoch and the
+//! counters of the chain, at a given block. Together with the protocol
+//! parameters it was computed with, it is enough to resume the chain
+//! state without replaying the blocks from the genesis.
+//!
+//! Snapshots of the state are written periodically by the blockchain,
+//! one file per block hash in the snapshot directory. The snapshots are
+//! encoded and written by a thread of their own, so the blockchain is
+//! only locked while the state is copied. Each file starts with the
+//! version of its format and the height of its block, the snapshots of
+//! the older versions are migrated when they are loaded.
+//!
+//! Next to each snapshot, the `.chain` file keeps the hashes of the
+//! main chain up to its block, so the height index of the main chain is
+//! resumed without walking the chain back to the genesis.
+//!
 
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use bincode;
+use xblockchain::address::StakeholderId;
+use cbor_event::se::Serializer;
+use xblockchain::block::{normal, Block, BlockDate, ChainState, EpochSlotId};
+use xblockchain::config::ProtocolMagic;
+use xblockchain::fee::LinearFee;
+use xblockchain::hash::Blake2b256;
+
+use blockcfg::{BlockHash, GenesisData, TxOut, TxoPointer};
+
+/// the version of the snapshot format written by this node
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// number of snapshots kept in the snapshot directory, the ones of the
+/// lowest blocks are removed
+const SNAPSHOTS_KEPT: usize = 3;
+
+pub type Utxos = BTreeMap<TxoPointer, TxOut>;
+
+/// the protocol parameters the state was computed with, from the
+/// genesis data
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolParameters {
+    pub protocol_magic: ProtocolMagic,
+    pub fee_policy: LinearFee,
+    pub epoch_stability_depth: usize,
+    pub slot_duration: Duration,
+}
+
+impl ProtocolParameters {
+    pub fn from_genesis(genesis_data: &GenesisData) -> Self {
+        ProtocolParameters {
+            protocol_magic: genesis_data.protocol_magic,
+            fee_policy: genesis_data.fee_policy,
+            epoch_stability_depth: genesis_data.epoch_stability_depth,
+            slot_duration: genesis_data.slot_duration,
+        }
+    }
+}
+
+/// the date of a block: the epoch, and the slot unless it is the
+/// boundary block of the epoch
+type SnapshotDate = (u64, Option<u16>);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct State {
-    // utxo, protocol parameters
+    /// the block the state is the result of
+    pub block: BlockHash,
+    pub date: Option<SnapshotDate>,
+    pub last_boundary_block: Option<BlockHash>,
+    pub chain_length: u64,
+    pub slot_leaders: Vec<StakeholderId>,
+    pub utxos: Utxos,
+    pub nr_transactions: u64,
+    pub spent_txos: u64,
+    pub parameters: ProtocolParameters,
 }
 
 impl State {
-    pub fn new() -> Self {
-        State {}
+    pub fn from_chain_state(genesis_data: &GenesisData, chain_state: &ChainState) -> Self {
+        State {
+            block: chain_state.last_block.clone(),
+            date: chain_state.last_date.map(|date| match date {
+                BlockDate::Boundary(epoch) => (epoch, None),
+                BlockDate::Normal(slot) => (slot.epoch, Some(slot.slotid)),
+            }),
+            last_boundary_block: chain_state.last_boundary_block.clone(),
+            chain_length: chain_state.chain_length,
+            slot_leaders: chain_state.slot_leaders.clone(),
+            utxos: chain_state.utxos.clone(),
+            nr_transactions: chain_state.nr_transactions,
+            spent_txos: chain_state.spent_txos,
+            parameters: ProtocolParameters::from_genesis(genesis_data),
+        }
+    }
+
+    /// the chain state at the block of the snapshot, `None` if the
+    /// state was computed with other protocol parameters.
+    pub fn to_chain_state(&self, genesis_data: &GenesisData) -> Option<ChainState> {
+        if self.parameters != ProtocolParameters::from_genesis(genesis_data) {
+            return None;
+        }
+        let mut chain_state = ChainState::new(genesis_data);
+        chain_state.last_block = self.block.clone();
+        chain_state.last_date = self.date.map(|(epoch, slot)| match slot {
+            None => BlockDate::Boundary(epoch),
+            Some(slotid) => BlockDate::Normal(EpochSlotId { epoch: epoch, slotid: slotid }),
+        });
+        chain_state.last_boundary_block = self.last_boundary_block.clone();
+        chain_state.chain_length = self.chain_length;
+        chain_state.slot_leaders = self.slot_leaders.clone();
+        chain_state.utxos = self.utxos.clone();
+        chain_state.nr_transactions = self.nr_transactions;
+        chain_state.spent_txos = self.spent_txos;
+        Some(chain_state)
+    }
+}
+
+/// check the body of the block is the one its header commits to: the
+/// hash of a block only covers its header
+pub fn check_body_proof(hash: &BlockHash, block: &Block) -> Result<(), String> {
+    let matches = match block {
+        Block::MainBlock(ref blk) => normal::BodyProof::generate_from_body(&blk.body) == blk.header.body_proof,
+        Block::BoundaryBlock(ref blk) => {
+            let mut serializer = Serializer::new_vec();
+            serializer
+                .serialize(&blk.body)
+                .map_err(|err| format!("cannot encode the body of block {}: {:?}", hash, err))?;
+            Blake2b256::new(&serializer.finalize()) == blk.header.body_proof.0
+        }
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(format!("the body of block {} does not match its header", hash))
+    }
+}
+
+/// apply the block to the chain state without verifying it: the
+/// witnesses, fees and slot leaders are not checked, only that the
+/// body matches the header, the block follows the chain state and
+/// spends existing outputs. Used for
+/// the blocks authenticated otherwise (e.g. below a trusted
+/// checkpoint). The chain state is unchanged on error.
+pub fn apply_block_unverified(chain_state: &mut ChainState, hash: &BlockHash, block: &Block) -> Result<(), String> {
+    check_body_proof(hash, block)?;
+    let header = block.get_header();
+    if header.get_previous_header() != chain_state.last_block {
+        return Err(format!("block {} does not extend {}", hash, chain_state.last_block));
+    }
+    let date = header.get_blockdate();
+    if let Some(ref last_date) = chain_state.last_date {
+        if date <= *last_date {
+            return Err(format!("block {} is not after the date {:?}", hash, last_date));
+        }
+    }
+
+    // the outputs spent and created by the block, an output may be
+    // created and spent within the block
+    let mut spent = BTreeSet::new();
+    let mut created = Utxos::new();
+    let mut transactions = 0;
+    if let Block::MainBlock(ref blk) = block {
+        for txaux in blk.body.tx.iter() {
+            let id = txaux.tx.id();
+            for input in txaux.tx.inputs.iter() {
+                if created.remove(input).is_some() {
+                    continue;
+                }
+                if !chain_state.utxos.contains_key(input) || !spent.insert(input.clone()) {
+                    return Err(format!("transaction {} of block {} spends a missing output {:?}", id, hash, input));
+                }
+            }
+            for (index, output) in txaux.tx.outputs.iter().enumerate() {
+                created.insert(TxoPointer::new(id.clone(), index as u32), output.clone());
+            }
+            transactions += 1;
+        }
+    }
+
+    for input in spent.iter() {
+        chain_state.utxos.remove(input);
+    }
+    chain_state.spent_txos += spent.len() as u64;
+    chain_state.utxos.extend(created);
+    chain_state.nr_transactions += transactions;
+    if let Block::BoundaryBlock(ref blk) = block {
+        chain_state.slot_leaders = blk.body.slot_leaders.clone();
+        chain_state.last_boundary_block = Some(hash.clone());
+    }
+    chain_state.last_block = hash.clone();
+    chain_state.last_date = Some(date);
+    chain_state.chain_length += 1;
+    Ok(())
+}
+
+/// the directory of the state snapshots
+#[derive(Debug, Clone)]
+pub struct Snapshots {
+    dir: PathBuf,
+    /// held while a snapshot is written and the directory pruned
+    writing: Arc<Mutex<()>>,
+}
+
+impl Snapshots {
+    pub fn new(dir: PathBuf) -> Self {
+        Snapshots {
+            dir: dir,
+            writing: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn path(&self, block: &BlockHash) -> PathBuf {
+        self.dir.join(format!("{}", block))
+    }
+
+    fn main_chain_path(&self, block: &BlockHash) -> PathBuf {
+        self.dir.join(format!("{}.chain", block))
+    }
+
+    pub fn exists(&self, block: &BlockHash) -> bool {
+        self.path(block).is_file()
+    }
+
+    /// read the hashes of the main chain up to the given block, from
+    /// the genesis. `None` if they were not written with a snapshot of
+    /// this block.
+    pub fn read_main_chain(&self, block: &BlockHash) -> io::Result<Option<Vec<BlockHash>>> {
+        match fs::read(self.main_chain_path(block)) {
+            Ok(bytes) => bincode::deserialize(&bytes).map(Some).map_err(invalid_data),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// read the snapshot of the state at the given block, `None` if
+    /// there is no snapshot for this block.
+    pub fn read(&self, block: &BlockHash) -> io::Result<Option<State>> {
+        match fs::read(self.path(block)) {
+            Ok(bytes) => decode(&bytes).map(Some),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// write a snapshot of the state with the hashes of the main chain
+    /// up to its block, and remove the snapshots of the lowest blocks
+    pub fn write(&self, state: &State, main_chain: &[BlockHash]) -> io::Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        fs::create_dir_all(&self.dir)?;
+        let path = self.main_chain_path(&state.block);
+        let tmp = path.with_extension("chain-tmp");
+        fs::write(&tmp, bincode::serialize(main_chain).map_err(invalid_data)?)?;
+        fs::rename(tmp, path)?;
+        let path = self.path(&state.block);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, encode(state)?)?;
+        fs::rename(tmp, path)?;
+        self.prune()
+    }
+
+    /// remove the snapshots of the lowest blocks, and the snapshots
+    /// that cannot be read
+    fn prune(&self) -> io::Result<()> {
+        let mut snapshots = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some() {
+                continue;
+            }
+            match read_height(&path) {
+                Ok(height) => snapshots.push((height, path)),
+                Err(err) => {
+                    warn!("removing the unreadable state snapshot {}: {}", path.display(), err);
+                    remove_snapshot(&path)?;
+                }
+            }
+        }
+        snapshots.sort();
+        let remove = snapshots.len().saturating_sub(SNAPSHOTS_KEPT);
+        for (_, path) in snapshots.into_iter().take(remove) {
+            remove_snapshot(&path)?;
+        }
+        Ok(())
+    }
+}
+
+/// remove the snapshot file and the main chain saved with it
+fn remove_snapshot(path: &Path) -> io::Result<()> {
+    fs::remove_file(path)?;
+    match fs::remove_file(path.with_extension("chain")) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn ::std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// writes the snapshots of the blockchain in a thread of its own. A
+/// snapshot requested while the previous one is still being written is
+/// skipped.
+pub struct SnapshotWriter {
+    sender: mpsc::SyncSender<(State, Vec<BlockHash>)>,
+}
+
+impl SnapshotWriter {
+    pub fn spawn(snapshots: Snapshots) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel::<(State, Vec<BlockHash>)>(1);
+        thread::Builder::new().name("snapshot writer".to_string()).spawn(move || {
+            for (state, main_chain) in receiver {
+                match snapshots.write(&state, &main_chain) {
+                    Ok(()) => debug!("state snapshot written at {}", state.block),
+                    Err(err) => warn!("cannot write the state snapshot at {}: {}", state.block, err),
+                }
+            }
+        })?;
+        Ok(SnapshotWriter { sender: sender })
+    }
+
+    pub fn write(&self, state: State, main_chain: Vec<BlockHash>) {
+        match self.sender.try_send((state, main_chain)) {
+            Ok(()) => {}
+            Err(mpsc::TrySendError::Full((state, _))) => {
+                warn!("a state snapshot is still being written, skipping the snapshot at {}", state.block)
+            }
+            Err(mpsc::TrySendError::Disconnected((state, _))) => {
+                warn!("the snapshot writer stopped, skipping the snapshot at {}", state.block)
+            }
+        }
+    }
+}
+
+/// the snapshot starts with the version of its format and the height of
+/// its block, so the snapshots are sorted without decoding them
+fn encode(state: &State) -> io::Result<Vec<u8>> {
+    let mut bytes = bincode::serialize(&SNAPSHOT_VERSION).map_err(invalid_data)?;
+    bytes.extend(bincode::serialize(&state.chain_length).map_err(invalid_data)?);
+    bytes.extend(bincode::serialize(state).map_err(invalid_data)?);
+    Ok(bytes)
+}
+
+/// the height of the block of the snapshot, the snapshots of the other
+/// versions are taken as the lowest.
+fn read_height(path: &Path) -> io::Result<u64> {
+    let mut file = fs::File::open(path)?;
+    let version: u32 = bincode::deserialize_from(&mut file).map_err(invalid_data)?;
+    if version != SNAPSHOT_VERSION {
+        return Ok(0);
+    }
+    bincode::deserialize_from(&mut file).map_err(invalid_data)
+}
+
+fn decode(bytes: &[u8]) -> io::Result<State> {
+    let version: u32 = bincode::deserialize(bytes).map_err(invalid_data)?;
+    let body = &bytes[bincode::serialized_size(&version).map_err(invalid_data)? as usize..];
+    match version {
+        SNAPSHOT_VERSION => {
+            let height: u64 = bincode::deserialize(body).map_err(invalid_data)?;
+            let body = &body[bincode::serialized_size(&height).map_err(invalid_data)? as usize..];
+            bincode::deserialize(body).map_err(invalid_data)
+        }
+        // the migrations from the older versions go here, decoding the
+        // old format and converting it to the current `State`
+        version => Err(invalid_data(format!(
+            "unsupported snapshot version {} (expected {})",
+            version, SNAPSHOT_VERSION
+        ))),
+    }
+}
+
+/// the snapshot directory, next to the blocks of the storage
+pub fn snapshot_dir(storage_path: &Path) -> PathBuf {
+    storage_path.join("state")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exe_common::genesisdata;
+    use std::{env, process};
+
+    fn genesis_data() -> GenesisData {
+        genesisdata::parse::parse(include_bytes!("../demo/demo-genesis.json"))
+    }
+
+    fn hash(n: u64) -> BlockHash {
+        format!("{:064x}", n).parse().unwrap()
+    }
+
+    /// the state of the genesis, at the block `hash(height)`
+    fn state(genesis_data: &GenesisData, height: u64) -> State {
+        let mut state = State::from_chain_state(genesis_data, &ChainState::new(genesis_data));
+        state.block = hash(height);
+        state.chain_length = height;
+        state
+    }
+
+    #[test]
+    fn encode_decode() {
+        let genesis_data = genesis_data();
+        let state = state(&genesis_data, 42);
+        let decoded = decode(&encode(&state).unwrap()).unwrap();
+        assert_eq!(bincode::serialize(&decoded).unwrap(), bincode::serialize(&state).unwrap());
+
+        let mut bytes = encode(&state).unwrap();
+        bytes[0] = 0xff;
+        assert!(decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn chain_state_round_trip() {
+        let genesis_data = genesis_data();
+        let mut chain_state = ChainState::new(&genesis_data);
+        chain_state.last_block = hash(7);
+        chain_state.last_date = Some(BlockDate::Normal(EpochSlotId { epoch: 1, slotid: 3 }));
+        chain_state.chain_length = 7;
+        chain_state.nr_transactions = 5;
+        chain_state.spent_txos = 2;
+
+        let state = State::from_chain_state(&genesis_data, &chain_state);
+        let restored = state.to_chain_state(&genesis_data).unwrap();
+        assert_eq!(restored.last_block, chain_state.last_block);
+        assert_eq!(restored.last_date, chain_state.last_date);
+        assert_eq!(restored.last_boundary_block, chain_state.last_boundary_block);
+        assert_eq!(restored.chain_length, chain_state.chain_length);
+        assert_eq!(restored.slot_leaders, chain_state.slot_leaders);
+        assert_eq!(restored.utxos, chain_state.utxos);
+        assert_eq!(restored.nr_transactions, chain_state.nr_transactions);
+        assert_eq!(restored.spent_txos, chain_state.spent_txos);
+
+        // computed with other protocol parameters
+        let mut state = state;
+        state.parameters.epoch_stability_depth += 1;
+        assert!(state.to_chain_state(&genesis_data).is_none());
+    }
+
+    #[test]
+    fn prune_keeps_the_highest_snapshots() {
+        let dir = env::temp_dir().join(format!("xchain-snapshots-{}", process::id()));
+        let snapshots = Snapshots::new(dir.clone());
+        let genesis_data = genesis_data();
+        let heights = (1..=SNAPSHOTS_KEPT as u64 + 2).collect::<Vec<_>>();
+        for height in heights.iter() {
+            snapshots.write(&state(&genesis_data, *height), &[hash(*height)]).unwrap();
+        }
+        // an unreadable snapshot is removed with the lowest ones
+        fs::write(dir.join(format!("{}", hash(100))), b"").unwrap();
+        snapshots.prune().unwrap();
+
+        let kept = heights.iter().filter(|height| snapshots.exists(&hash(**height))).cloned().collect::<Vec<_>>();
+        let main_chains = heights
+            .iter()
+            .filter(|height| snapshots.read_main_chain(&hash(**height)).unwrap().is_some())
+            .cloned()
+            .collect::<Vec<_>>();
+        let unreadable = snapshots.exists(&hash(100));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(kept, heights[heights.len() - SNAPSHOTS_KEPT..].to_vec());
+        assert_eq!(main_chains, kept);
+        assert!(!unreadable);
     }
 }
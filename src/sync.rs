@@ -0,0 +1,476 @@
+//! headers first synchronisation
+//!
+//! The chain between our tip and a tip of the network is downloaded in
+//! two steps, pipelined:
+//!
+//! 1. the headers are downloaded from one peer, batch after batch.
+//!    Checking they form a chain connected to our own, signed by the
+//!    leaders of their slots, is cheap and tells us exactly which
+//!    blocks we need;
+//! 2. as the batches of headers arrive, the block bodies are split in
+//!    batches downloaded concurrently from all the given peers. The
+//!    batches complete in any order but are applied to the blockchain
+//!    in the order of the chain.
+//!
+//! A batch that fails (the peer disconnected, timed out or sent an
+//! unexpected block) is given to another peer, and the faulty peer is
+//! not used for the rest of the synchronisation.
+//!
+//! If a trusted checkpoint is configured, the blocks up to the
+//! checkpoint are authenticated by the chain of headers ending at the
+//! checkpoint hash and by the body proof of these headers, and are
+//! applied without the full verification. They are only downloaded
+//! once the chain of headers reached the checkpoint.
+//!
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Debug,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use blockcfg::{Block, BlockHash, Header};
+use blockchain::{BlockStatus, BlockchainR};
+use bootstrap::SyncStatus;
+use futures::Future;
+use intercom::{reply_channel, BoxReply, Error, PeerMsg, Reply, StreamReply};
+use network::{PeerHandle, PeerId};
+use reputation::{Offence, ReputationR};
+use state::check_body_proof;
+use xblockchain::address::StakeholderId;
+use xblockchain::block::{verify::Verify, BlockDate, BlockSignature, ChainState};
+
+/// number of blocks requested at once to a peer
+const BLOCK_BATCH_SIZE: usize = 500;
+
+/// number of batches downloaded at the same time from one peer
+const BATCHES_PER_PEER: usize = 2;
+
+/// how often the progress of the download is logged
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// how long to wait for a reply of the peers before giving up, the
+/// requests time out individually before this
+const REPLY_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// send a request to the peer and wait for the reply
+pub fn request<T, F>(peer: &PeerHandle, f: F) -> Result<T, String>
+where
+    T: Debug + Send + 'static,
+    F: FnOnce(BoxReply<T>) -> PeerMsg,
+{
+    let (handle, reply) = reply_channel();
+    peer.send(f(Box::new(handle)))
+        .map_err(|_| "peer disconnected".to_owned())?;
+    reply.wait().map_err(|err| err.to_string())
+}
+
+/// identifier of a `GetBlocks` request, a batch given to another peer
+/// is a new request so late replies to the failed one are ignored.
+type RequestId = usize;
+
+#[derive(Debug)]
+enum StreamItem {
+    Block(Block),
+    Error(Error),
+    Close,
+}
+
+/// the replies of the requests of a synchronisation are all sent on the
+/// same channel
+#[derive(Debug)]
+enum Event {
+    Headers(Result<Vec<Header>, Error>),
+    Blocks(RequestId, StreamItem),
+}
+
+#[derive(Debug)]
+struct HeadersReply {
+    sender: mpsc::Sender<Event>,
+}
+
+impl Reply<Vec<Header>> for HeadersReply {
+    fn reply_ok(&mut self, headers: Vec<Header>) {
+        let _ = self.sender.send(Event::Headers(Ok(headers)));
+    }
+    fn reply_error(&mut self, error: Error) {
+        let _ = self.sender.send(Event::Headers(Err(error)));
+    }
+}
+
+/// the replies of the `GetBlocks` requests, tagged with the request
+/// they belong to.
+#[derive(Debug)]
+struct BatchReply {
+    request: RequestId,
+    sender: mpsc::Sender<Event>,
+}
+
+impl StreamReply<Block> for BatchReply {
+    fn send(&mut self, item: Block) {
+        let _ = self.sender.send(Event::Blocks(self.request, StreamItem::Block(item)));
+    }
+    fn send_error(&mut self, error: Error) {
+        let _ = self.sender.send(Event::Blocks(self.request, StreamItem::Error(error)));
+    }
+    fn close(&mut self) {
+        let _ = self.sender.send(Event::Blocks(self.request, StreamItem::Close));
+    }
+}
+
+/// check the header is signed by the leader of its slot. The leaders
+/// are only known for the epoch of our tip, the headers of the later
+/// epochs are checked with their blocks.
+fn verify_header(chain_state: &ChainState, header: &Header) -> Result<(), String> {
+    header
+        .verify()
+        .map_err(|err| format!("invalid header {}: {:?}", header.compute_hash(), err))?;
+
+    let slot = match header.get_blockdate() {
+        BlockDate::Boundary(_) => return Ok(()),
+        BlockDate::Normal(slot) => slot,
+    };
+    let epoch = match chain_state.last_date {
+        None => 0,
+        Some(BlockDate::Boundary(epoch)) => epoch,
+        Some(BlockDate::Normal(ref date)) => date.epoch,
+    };
+    if slot.epoch != epoch {
+        return Ok(());
+    }
+    let leader = chain_state
+        .slot_leaders
+        .get(slot.slotid as usize)
+        .ok_or_else(|| format!("header {} is in the unknown slot {:?}", header.compute_hash(), slot))?;
+    let issuer = match header {
+        Header::MainBlockHeader(ref hdr) => match hdr.consensus.block_signature {
+            BlockSignature::ProxyHeavy(ref proxy) => StakeholderId::new(&proxy.psk.issuer_pk),
+            _ => return Err(format!("header {} is not signed by a delegate", header.compute_hash())),
+        },
+        Header::BoundaryBlockHeader(_) => return Ok(()),
+    };
+    if issuer != *leader {
+        return Err(format!("header {} is not signed by the leader of the slot {:?}", header.compute_hash(), slot));
+    }
+    Ok(())
+}
+
+/// the download of the headers from one peer, a batch at a time
+struct HeaderDownload {
+    peer: PeerHandle,
+    tip: BlockHash,
+    sender: mpsc::Sender<Event>,
+    /// hash and date of the last header received
+    last: Option<(BlockHash, BlockDate)>,
+    received: usize,
+    done: bool,
+}
+
+impl HeaderDownload {
+    fn start(peer: &PeerHandle, tip: &BlockHash, sender: mpsc::Sender<Event>, checkpoints: Vec<BlockHash>) -> Result<Self, String> {
+        let download = HeaderDownload {
+            peer: peer.clone(),
+            tip: tip.clone(),
+            sender: sender,
+            last: None,
+            received: 0,
+            done: false,
+        };
+        download.request(checkpoints)?;
+        Ok(download)
+    }
+
+    fn request(&self, checkpoints: Vec<BlockHash>) -> Result<(), String> {
+        let reply = HeadersReply { sender: self.sender.clone() };
+        self.peer
+            .send(PeerMsg::GetBlockHeaders(checkpoints, self.tip.clone(), Box::new(reply)))
+            .map_err(|_| "peer disconnected".to_owned())
+    }
+}
+
+/// a batch being downloaded
+struct InFlight {
+    batch: usize,
+    peer: PeerId,
+    blocks: Vec<Block>,
+}
+
+/// the download of the blocks of the headers received so far
+struct BlockDownload {
+    peers: Vec<PeerHandle>,
+    sender: mpsc::Sender<Event>,
+    /// the headers of the blocks to download, in batches in the order
+    /// of the chain
+    batches: Vec<Vec<Header>>,
+    pending: VecDeque<usize>,
+    in_flight: HashMap<RequestId, InFlight>,
+    completed: BTreeMap<usize, (PeerId, Vec<Block>)>,
+    next_request: RequestId,
+    /// the next batch to apply
+    next_batch: usize,
+    headers: usize,
+    applied: usize,
+}
+
+impl BlockDownload {
+    fn new(peers: Vec<PeerHandle>, sender: mpsc::Sender<Event>) -> Self {
+        BlockDownload {
+            peers: peers,
+            sender: sender,
+            batches: Vec::new(),
+            pending: VecDeque::new(),
+            in_flight: HashMap::new(),
+            completed: BTreeMap::new(),
+            next_request: 0,
+            next_batch: 0,
+            headers: 0,
+            applied: 0,
+        }
+    }
+
+    fn add_headers(&mut self, headers: Vec<Header>) {
+        self.headers += headers.len();
+        for chunk in headers.chunks(BLOCK_BATCH_SIZE) {
+            self.pending.push_back(self.batches.len());
+            self.batches.push(chunk.to_vec());
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.headers - self.applied
+    }
+
+    fn is_done(&self) -> bool {
+        self.next_batch == self.batches.len()
+    }
+
+    /// give the pending batches to the peers with free slots
+    fn request_batches(&mut self) -> Result<(), String> {
+        let mut i = 0;
+        while i < self.peers.len() && !self.pending.is_empty() {
+            let busy = self.in_flight.values().filter(|r| r.peer == self.peers[i].id).count();
+            if busy >= BATCHES_PER_PEER {
+                i += 1;
+                continue;
+            }
+            let batch = self.pending.pop_front().unwrap();
+            let from = self.batches[batch].first().unwrap().compute_hash();
+            let to = self.batches[batch].last().unwrap().compute_hash();
+            let reply = BatchReply { request: self.next_request, sender: self.sender.clone() };
+            match self.peers[i].send(PeerMsg::GetBlocks(from, to, Box::new(reply))) {
+                Ok(()) => {
+                    let peer = self.peers[i].id;
+                    self.in_flight.insert(self.next_request, InFlight { batch, peer, blocks: Vec::new() });
+                    self.next_request += 1;
+                }
+                Err(_) => {
+                    debug!("sync: {} disconnected", self.peers[i].connection);
+                    self.pending.push_front(batch);
+                    self.peers.remove(i);
+                }
+            }
+        }
+        if self.in_flight.is_empty() && !self.pending.is_empty() {
+            return Err("no peer left to download the blocks from".to_owned());
+        }
+        Ok(())
+    }
+
+    /// record an item of the reply to a request, returns the peer to
+    /// report if it sent unexpected blocks
+    fn received(&mut self, request: RequestId, item: StreamItem) -> Option<PeerHandle> {
+        let done = match (self.in_flight.get_mut(&request), item) {
+            // late reply to a request given up already
+            (None, _) => return None,
+            (Some(r), StreamItem::Block(block)) => {
+                r.blocks.push(block);
+                false
+            }
+            (Some(_), StreamItem::Error(err)) => {
+                debug!("sync: error while downloading blocks: {}", err);
+                true
+            }
+            (Some(_), StreamItem::Close) => true,
+        };
+        if !done {
+            return None;
+        }
+
+        let r = self.in_flight.remove(&request).unwrap();
+        let expected = &self.batches[r.batch];
+        let unexpected = r.blocks.len() > expected.len()
+            || r.blocks.iter().zip(expected.iter()).any(|(block, header)| {
+                let hash = block.get_header().compute_hash();
+                hash != header.compute_hash() || check_body_proof(&hash, block).is_err()
+            });
+        if !unexpected && r.blocks.len() == expected.len() {
+            self.completed.insert(r.batch, (r.peer, r.blocks));
+            return None;
+        }
+
+        // try with another peer, this one is not used anymore
+        self.pending.push_back(r.batch);
+        let peer = self.peers.iter().position(|p| p.id == r.peer)?;
+        let peer = self.peers.remove(peer);
+        warn!("sync: {} did not send the expected blocks", peer.connection);
+        if unexpected { Some(peer) } else { None }
+    }
+}
+
+pub struct Synchroniser {
+    blockchain: BlockchainR,
+    reputation: ReputationR,
+    status: SyncStatus,
+    /// hash of a trusted block of the chain
+    checkpoint: Option<BlockHash>,
+}
+
+impl Synchroniser {
+    pub fn new(
+        blockchain: BlockchainR,
+        reputation: ReputationR,
+        status: SyncStatus,
+        checkpoint: Option<BlockHash>,
+    ) -> Self {
+        Synchroniser {
+            blockchain,
+            reputation,
+            status,
+            checkpoint,
+        }
+    }
+
+    /// the trusted checkpoint, if our chain does not contain it yet
+    pub fn pending_checkpoint(&self) -> Option<&BlockHash> {
+        self.checkpoint
+            .as_ref()
+            .filter(|hash| !self.blockchain.read().unwrap().block_exists(hash))
+    }
+
+    fn report(&self, peer: &PeerHandle, offence: Offence) {
+        self.reputation.write().unwrap().report(&peer.connection, offence);
+    }
+
+    /// download and apply the chain from our tip to the given tip. The
+    /// headers are downloaded from `peer`, the blocks from all the
+    /// `peers` as the headers arrive.
+    pub fn sync(&self, peer: &PeerHandle, peers: Vec<PeerHandle>, tip: &BlockHash) -> Result<(), String> {
+        // the blocks of the chain ending at the checkpoint don't need
+        // the verification, they are only downloaded once the headers
+        // reached the checkpoint
+        let trusted = self.pending_checkpoint() == Some(tip);
+
+        let (sender, events) = mpsc::channel();
+        let checkpoints = {
+            let blockchain = self.blockchain.read().unwrap();
+            vec![blockchain.get_tip(), blockchain.get_genesis_hash().clone()]
+        };
+        let mut headers = HeaderDownload::start(peer, tip, sender.clone(), checkpoints)?;
+        let mut blocks = BlockDownload::new(peers, sender);
+        let mut last_progress = Instant::now();
+
+        while !(headers.done && blocks.is_done()) {
+            if !trusted || headers.done {
+                blocks.request_batches()?;
+            }
+
+            let event = events
+                .recv_timeout(REPLY_TIMEOUT)
+                .map_err(|_| format!("no reply from the peers in {:?}", REPLY_TIMEOUT))?;
+            match event {
+                Event::Headers(result) => {
+                    let batch = self.headers_received(&mut headers, result)?;
+                    blocks.add_headers(batch);
+                    self.status.set_distance(blocks.remaining());
+                }
+                Event::Blocks(request, item) => {
+                    if let Some(peer) = blocks.received(request, item) {
+                        self.report(&peer, Offence::InvalidBlock);
+                    }
+                }
+            }
+
+            self.apply_blocks(&mut blocks, trusted)?;
+
+            if last_progress.elapsed() > PROGRESS_INTERVAL {
+                info!("sync: {}/{} blocks applied, downloading from {} peers",
+                      blocks.applied, blocks.headers, blocks.peers.len());
+                last_progress = Instant::now();
+            }
+        }
+        info!("sync: {} blocks applied", blocks.applied);
+        Ok(())
+    }
+
+    /// check a batch of headers extends our chain (the first batch) or
+    /// the headers downloaded so far, and request the next batch.
+    fn headers_received(&self, download: &mut HeaderDownload, result: Result<Vec<Header>, Error>) -> Result<Vec<Header>, String> {
+        let batch = result.map_err(|err| format!("cannot download the headers: {}", err))?;
+        if batch.is_empty() {
+            return Err("peer sent no header".to_owned());
+        }
+
+        if download.last.is_none() {
+            let parent = batch[0].get_previous_header();
+            if !self.blockchain.read().unwrap().block_exists(&parent) {
+                self.report(&download.peer, Offence::InvalidBlock);
+                return Err(format!("headers do not connect to our chain ({})", parent));
+            }
+        }
+        let chain_state = self.blockchain.read().unwrap().get_chain_state().clone();
+        for header in batch.iter() {
+            if let Some((ref hash, ref date)) = download.last {
+                if header.get_previous_header() != *hash || header.get_blockdate() <= *date {
+                    self.report(&download.peer, Offence::InvalidBlock);
+                    return Err(format!("invalid header chain at {}", header.compute_hash()));
+                }
+            }
+            if let Err(err) = verify_header(&chain_state, header) {
+                self.report(&download.peer, Offence::InvalidBlock);
+                return Err(err);
+            }
+            download.last = Some((header.compute_hash(), header.get_blockdate()));
+        }
+        download.received += batch.len();
+        info!("sync: downloaded {} headers", download.received);
+
+        let last = batch.last().unwrap().compute_hash();
+        if last == download.tip {
+            download.done = true;
+        } else {
+            download.request(vec![last])?;
+        }
+        Ok(batch)
+    }
+
+    /// apply the downloaded batches that are next in the chain
+    fn apply_blocks(&self, download: &mut BlockDownload, trusted: bool) -> Result<(), String> {
+        while let Some((peer, blocks)) = download.completed.remove(&download.next_batch) {
+            for block in blocks {
+                let hash = block.get_header().compute_hash();
+                if trusted {
+                    self.blockchain.write().unwrap().handle_trusted_block(block)?;
+                    download.applied += 1;
+                    continue;
+                }
+                match self.blockchain.write().unwrap().handle_incoming_block(block) {
+                    BlockStatus::Connected => {}
+                    BlockStatus::Unconnected => {
+                        return Err(format!("block {} does not connect", hash))
+                    }
+                    BlockStatus::Invalid(err) => {
+                        if let Some(peer) = download.peers.iter().find(|p| p.id == peer) {
+                            self.report(peer, Offence::InvalidBlock);
+                        }
+                        return Err(format!("invalid block {}: {}", hash, err));
+                    }
+                }
+                download.applied += 1;
+            }
+            download.next_batch += 1;
+            self.status.set_distance(download.remaining());
+        }
+        Ok(())
+    }
+}
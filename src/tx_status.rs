@@ -0,0 +1,571 @@
+//! status of the transactions
+//!
+//! The tracker follows the transactions of the pool until they are
+//! final: pending in the pool, included in a block of our chain at a
+//! given depth, expired after staying too long in the pool, or
+//! rejected as a double spend when another transaction of our chain
+//! spent one of their inputs (e.g. after a switch to another fork).
+//! Only the pending transactions stay in the pool, the other ones are
+//! removed so they are not put in a block again.
+//!
+//! The tracker keeps the last `TRACKING_DEPTH` blocks of our chain.
+//! On a tip change it finds the fork point in these blocks from the
+//! height index of our chain: the transactions of the abandoned blocks
+//! are pending again and go back to the pool, the ones of the new
+//! blocks are included. The transactions deeper than `TRACKING_DEPTH`
+//! are final, they are forgotten after a while like the expired ones.
+//!
+//! When our chain switches to a fork older than all the tracked blocks,
+//! the tracked chain is abandoned as a whole. The inclusion of its
+//! transactions in the blocks of the new fork below the tracked depth
+//! is unknown: the ones whose inputs are still unspent are pending
+//! again, the other ones are not tracked anymore.
+//!
+//! The clients query the status of a transaction, or subscribe to the
+//! status changes of some or all the transactions.
+//!
+
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    io,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use futures::{prelude::*, sync::mpsc};
+use tokio::timer::Interval;
+use xblockchain::block::Block;
+use xblockchain_storage::{block_read, Storage};
+
+use blockcfg::{BlockHash, Transaction, TransactionId};
+use blockchain::{BlockchainR, MainChain};
+use intercom::BoxStreamReply;
+use utils::task::{run_blocking, Shutdown};
+use TPoolR;
+
+/// number of blocks of our chain the tracker keeps, the transactions
+/// included deeper than this are final
+const TRACKING_DEPTH: usize = 100;
+
+/// the pending transactions expire after this time
+const TRANSACTION_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+/// the expired, double spent and final transactions are forgotten
+/// after this time
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// how often the pool is checked for new and expired transactions
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TxStatus {
+    /// in the pool, waiting to be included in a block
+    Pending,
+    /// in a block of our chain, `depth` blocks below the tip (0 for a
+    /// transaction of the tip)
+    InBlock { block: BlockHash, depth: u64 },
+    /// stayed in the pool longer than the expiry time
+    Expired,
+    /// an input is spent by another transaction of our chain
+    DoubleSpend,
+}
+
+#[derive(Debug, Clone)]
+enum State {
+    Pending,
+    Included { block: BlockHash, height: u64 },
+    /// included below the tracked blocks
+    Final { block: BlockHash, height: u64 },
+    Expired,
+    DoubleSpend,
+}
+
+impl State {
+    fn is_pending(&self) -> bool {
+        match self {
+            State::Pending => true,
+            _ => false,
+        }
+    }
+}
+
+struct Tracked {
+    /// kept to put the transaction back in the pool when its block is
+    /// abandoned
+    tx: Transaction,
+    state: State,
+    /// when the transaction entered its current state
+    since: Instant,
+    /// the last status sent to the subscribers
+    notified: Option<TxStatus>,
+}
+
+impl Tracked {
+    fn set_state(&mut self, state: State) {
+        self.state = state;
+        self.since = Instant::now();
+    }
+}
+
+struct Subscriber {
+    /// the transactions followed, all of them if `None`
+    ids: Option<BTreeSet<TransactionId>>,
+    reply: BoxStreamReply<(TransactionId, TxStatus)>,
+}
+
+/// a block of the tracked part of our chain
+struct TrackedBlock<Hash, Id> {
+    hash: Hash,
+    height: u64,
+    transactions: Vec<Id>,
+}
+
+/// the changes of the tracked chain on a tip change
+struct ChainUpdate<Hash, Id> {
+    /// the transactions of the abandoned blocks
+    abandoned: Vec<Id>,
+    /// all the tracked blocks were abandoned, the fork point is below
+    /// them
+    deep_reorg: bool,
+    /// the transactions of the new blocks, with their block and height
+    included: Vec<(Id, Hash, u64)>,
+    /// the transactions of the blocks leaving the tracked chain
+    finalised: Vec<(Id, Hash, u64)>,
+}
+
+/// the last blocks of our chain, the oldest first
+struct TrackedChain<Hash, Id> {
+    blocks: VecDeque<TrackedBlock<Hash, Id>>,
+}
+
+impl<Hash: Ord + Clone, Id: PartialEq + Clone> TrackedChain<Hash, Id> {
+    fn new() -> Self {
+        TrackedChain { blocks: VecDeque::new() }
+    }
+
+    /// the height of the newest tracked block still on our chain
+    fn fork_point(&self, main_chain: &MainChain<Hash>) -> Option<u64> {
+        self.blocks
+            .iter()
+            .rev()
+            .find(|block| main_chain.get(block.height as usize) == Some(&block.hash))
+            .map(|block| block.height)
+    }
+
+    /// the block of the tracked chain including the transaction, and
+    /// its height
+    fn find(&self, id: &Id) -> Option<(Hash, u64)> {
+        self.blocks
+            .iter()
+            .find(|block| block.transactions.contains(id))
+            .map(|block| (block.hash.clone(), block.height))
+    }
+
+    /// replace the blocks above the fork point (all of them if `None`)
+    /// by the given blocks, in the order of the chain
+    fn update(&mut self, fork: Option<u64>, blocks: Vec<TrackedBlock<Hash, Id>>) -> ChainUpdate<Hash, Id> {
+        let deep_reorg = fork.is_none() && !self.blocks.is_empty();
+        let mut abandoned = Vec::new();
+        while self.blocks.back().map(|block| fork.map(|fork| block.height > fork).unwrap_or(true)) == Some(true) {
+            abandoned.extend(self.blocks.pop_back().unwrap().transactions);
+        }
+
+        let mut included = Vec::new();
+        for block in blocks {
+            for id in block.transactions.iter() {
+                included.push((id.clone(), block.hash.clone(), block.height));
+            }
+            self.blocks.push_back(block);
+        }
+
+        let mut finalised = Vec::new();
+        while self.blocks.len() > TRACKING_DEPTH {
+            let block = self.blocks.pop_front().unwrap();
+            for id in block.transactions {
+                finalised.push((id, block.hash.clone(), block.height));
+            }
+        }
+
+        ChainUpdate {
+            abandoned: abandoned,
+            deep_reorg: deep_reorg,
+            included: included,
+            finalised: finalised,
+        }
+    }
+}
+
+/// the blocks of our chain above the fork point with the tracked
+/// chain. Without a fork point only the last `TRACKING_DEPTH` blocks
+/// are tracked, the height 0 is the genesis and not a block.
+fn blocks_above<Hash: Ord + Clone>(main_chain: &MainChain<Hash>, fork: Option<u64>) -> Vec<(u64, Hash)> {
+    let tip_height = main_chain.tip_height() as u64;
+    let start = match fork {
+        Some(fork) => fork + 1,
+        None => tip_height.saturating_sub(TRACKING_DEPTH as u64 - 1).max(1),
+    };
+    (start..=tip_height)
+        .filter_map(|height| main_chain.get(height as usize).map(|hash| (height, hash.clone())))
+        .collect()
+}
+
+pub struct Tracker {
+    transactions: BTreeMap<TransactionId, Tracked>,
+    chain: TrackedChain<BlockHash, TransactionId>,
+    tip_height: u64,
+    subscribers: Vec<Subscriber>,
+}
+
+pub type TrackerR = Arc<RwLock<Tracker>>;
+
+fn block_transaction_ids(block: &Block) -> Vec<TransactionId> {
+    match block {
+        Block::BoundaryBlock(_) => Vec::new(),
+        Block::MainBlock(blk) => blk.body.tx.iter().map(|tx| tx.tx.id()).collect(),
+    }
+}
+
+fn to_status(state: &State, tip_height: u64) -> TxStatus {
+    match state {
+        State::Pending => TxStatus::Pending,
+        State::Included { block, height } | State::Final { block, height } => TxStatus::InBlock {
+            block: block.clone(),
+            depth: tip_height.saturating_sub(*height),
+        },
+        State::Expired => TxStatus::Expired,
+        State::DoubleSpend => TxStatus::DoubleSpend,
+    }
+}
+
+impl Tracker {
+    pub fn new() -> Self {
+        Tracker {
+            transactions: BTreeMap::new(),
+            chain: TrackedChain::new(),
+            tip_height: 0,
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// the status of the transaction, `None` if it is not tracked
+    pub fn get(&self, id: &TransactionId) -> Option<TxStatus> {
+        self.transactions.get(id).map(|tracked| to_status(&tracked.state, self.tip_height))
+    }
+
+    /// send the status changes of the given transactions (of all the
+    /// transactions if empty) to the reply. The current status of the
+    /// tracked ones is sent first.
+    pub fn subscribe(&mut self, ids: Vec<TransactionId>, mut reply: BoxStreamReply<(TransactionId, TxStatus)>) {
+        let ids = if ids.is_empty() { None } else { Some(ids.into_iter().collect::<BTreeSet<_>>()) };
+        for (id, tracked) in self.transactions.iter() {
+            if ids.as_ref().map(|ids| ids.contains(id)).unwrap_or(true) {
+                reply.send((id.clone(), to_status(&tracked.state, self.tip_height)));
+            }
+        }
+        self.subscribers.push(Subscriber { ids: ids, reply: reply });
+    }
+
+    /// track the new transactions of the pool, the ones already
+    /// included, final or rejected are removed from the pool
+    fn track_pool(&mut self, tpool: &TPoolR) {
+        let mut tpool = tpool.write().unwrap();
+        let mut settled = Vec::new();
+        for (id, (_, tx)) in tpool.content.iter() {
+            if let Some(tracked) = self.transactions.get(id) {
+                if !tracked.state.is_pending() {
+                    settled.push(id.clone());
+                }
+                continue;
+            }
+            let state = match self.chain.find(id) {
+                Some((block, height)) => {
+                    settled.push(id.clone());
+                    State::Included { block: block, height: height }
+                }
+                None => State::Pending,
+            };
+            self.transactions.insert(id.clone(), Tracked {
+                tx: tx.clone(),
+                state: state,
+                since: Instant::now(),
+                notified: None,
+            });
+        }
+        for id in settled {
+            tpool.content.remove(&id);
+        }
+    }
+
+    /// follow our chain up to the current tip of the blockchain
+    fn follow_tip(&mut self, storage: &Storage, blockchain: &BlockchainR, tpool: &TPoolR) -> io::Result<()> {
+        let (tip_height, fork, hashes) = {
+            let blockchain = blockchain.read().unwrap();
+            let main_chain = blockchain.get_main_chain();
+            let fork = self.chain.fork_point(main_chain);
+            (main_chain.tip_height() as u64, fork, blocks_above(main_chain, fork))
+        };
+
+        let mut blocks = Vec::with_capacity(hashes.len());
+        for (height, hash) in hashes {
+            let block = match block_read(storage, &hash) {
+                None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("block {} not found", hash))),
+                Some(rblk) => rblk
+                    .decode()
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err)))?,
+            };
+            blocks.push(TrackedBlock {
+                hash: hash,
+                height: height,
+                transactions: block_transaction_ids(&block),
+            });
+        }
+
+        let update = self.chain.update(fork, blocks);
+        self.tip_height = tip_height;
+
+        // the transactions of the abandoned blocks whose inclusion in
+        // the new fork is unknown
+        let mut unknown = BTreeSet::new();
+        if update.deep_reorg {
+            warn!("our chain switched to a fork older than the {} tracked blocks", TRACKING_DEPTH);
+            let blockchain = blockchain.read().unwrap();
+            let utxos = blockchain.get_utxos();
+            let abandoned = update.abandoned.iter().collect::<BTreeSet<_>>();
+            let included = update.included.iter().map(|(id, _, _)| id).collect::<BTreeSet<_>>();
+            for id in update.abandoned.iter().filter(|id| !included.contains(id)) {
+                if let Some(tracked) = self.transactions.get(id) {
+                    let spent = tracked
+                        .tx
+                        .tx
+                        .inputs
+                        .iter()
+                        .any(|input| !utxos.contains_key(input) && !abandoned.contains(&&input.id));
+                    if spent {
+                        unknown.insert(id.clone());
+                    }
+                }
+            }
+        }
+
+        let mut tpool = tpool.write().unwrap();
+        for id in update.abandoned {
+            if unknown.contains(&id) {
+                debug!("transaction {} of an abandoned fork is not tracked anymore", id);
+                self.transactions.remove(&id);
+                continue;
+            }
+            if let Some(tracked) = self.transactions.get_mut(&id) {
+                tracked.set_state(State::Pending);
+                tpool.add(id.clone(), tracked.tx.clone());
+            }
+        }
+        for (id, block, height) in update.included {
+            if let Some(tracked) = self.transactions.get_mut(&id) {
+                tracked.set_state(State::Included { block: block, height: height });
+            }
+            tpool.content.remove(&id);
+        }
+        for (id, block, height) in update.finalised {
+            if let Some(tracked) = self.transactions.get_mut(&id) {
+                tracked.set_state(State::Final { block: block, height: height });
+            }
+        }
+        Ok(())
+    }
+
+    /// expire the old pending transactions, and reject the ones whose
+    /// inputs are spent by our chain. They are removed from the pool.
+    fn check_pending(&mut self, blockchain: &BlockchainR, tpool: &TPoolR) {
+        let mut rejected = Vec::new();
+        {
+            let blockchain = blockchain.read().unwrap();
+            let utxos = blockchain.get_utxos();
+            // the outputs of the pending transactions may be spent by
+            // other pending transactions
+            let pending = self
+                .transactions
+                .iter()
+                .filter(|(_, tracked)| tracked.state.is_pending())
+                .map(|(id, _)| id.clone())
+                .collect::<BTreeSet<_>>();
+
+            for (id, tracked) in self.transactions.iter_mut() {
+                if !tracked.state.is_pending() {
+                    continue;
+                }
+                let spent = tracked
+                    .tx
+                    .tx
+                    .inputs
+                    .iter()
+                    .any(|input| !utxos.contains_key(input) && !pending.contains(&input.id));
+                if spent {
+                    tracked.set_state(State::DoubleSpend);
+                } else if tracked.since.elapsed() > TRANSACTION_EXPIRY {
+                    tracked.set_state(State::Expired);
+                } else {
+                    continue;
+                }
+                rejected.push(id.clone());
+            }
+        }
+
+        let mut tpool = tpool.write().unwrap();
+        for id in rejected {
+            tpool.content.remove(&id);
+        }
+
+        self.transactions.retain(|_, tracked| match tracked.state {
+            State::Expired | State::DoubleSpend | State::Final { .. } => tracked.since.elapsed() < FORGET_AFTER,
+            _ => true,
+        });
+    }
+
+    /// send the status changes to the subscribers
+    fn notify(&mut self) {
+        let mut changes = Vec::new();
+        let tip_height = self.tip_height;
+        for (id, tracked) in self.transactions.iter_mut() {
+            let status = to_status(&tracked.state, tip_height);
+            if tracked.notified.as_ref() != Some(&status) {
+                tracked.notified = Some(status.clone());
+                changes.push((id.clone(), status));
+            }
+        }
+
+        self.subscribers.retain(|subscriber| !subscriber.reply.is_cancelled());
+        for subscriber in self.subscribers.iter_mut() {
+            for (id, status) in changes.iter() {
+                if subscriber.ids.as_ref().map(|ids| ids.contains(id)).unwrap_or(true) {
+                    subscriber.reply.send((id.clone(), status.clone()));
+                }
+            }
+        }
+    }
+
+    pub fn update(&mut self, storage: &Storage, blockchain: &BlockchainR, tpool: &TPoolR) {
+        self.track_pool(tpool);
+        if let Err(err) = self.follow_tip(storage, blockchain, tpool) {
+            error!("cannot follow the tip of the chain: {}", err);
+        }
+        self.check_pending(blockchain, tpool);
+        self.notify();
+    }
+}
+
+/// update the statuses on the tip changes, and periodically for the
+/// changes of the pool, until the shutdown
+pub fn run(
+    tracker: TrackerR,
+    storage: Storage,
+    blockchain: BlockchainR,
+    tpool: TPoolR,
+    tips: mpsc::UnboundedReceiver<BlockHash>,
+    shutdown: Shutdown,
+) -> impl Future<Item = (), Error = ()> {
+    let storage = Arc::new(storage);
+    let ticks = Interval::new(Instant::now() + CHECK_INTERVAL, CHECK_INTERVAL)
+        .map(|_| ())
+        .map_err(|err| error!("transaction status timer failed: {}", err));
+    tips.map(|_| ())
+        .select(ticks)
+        .for_each(move |()| {
+            let tracker = tracker.clone();
+            let storage = storage.clone();
+            let blockchain = blockchain.clone();
+            let tpool = tpool.clone();
+            run_blocking(move || tracker.write().unwrap().update(&storage, &blockchain, &tpool))
+        })
+        .select(shutdown.wait())
+        .map(|_| ())
+        .map_err(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a chain whose block at height `h` has the hash `h`
+    fn main_chain(length: u32) -> MainChain<u32> {
+        let mut chain = MainChain::new(0);
+        for hash in 1..=length {
+            chain.push(hash);
+        }
+        chain
+    }
+
+    /// follow the main chain, each block has one transaction whose id
+    /// is the hash of the block
+    fn follow(tracked: &mut TrackedChain<u32, u32>, main_chain: &MainChain<u32>) -> ChainUpdate<u32, u32> {
+        let fork = tracked.fork_point(main_chain);
+        let blocks = blocks_above(main_chain, fork)
+            .into_iter()
+            .map(|(height, hash)| TrackedBlock { hash: hash, height: height, transactions: vec![hash] })
+            .collect();
+        tracked.update(fork, blocks)
+    }
+
+    fn ids(transactions: &[(u32, u32, u64)]) -> Vec<u32> {
+        transactions.iter().map(|(id, _, _)| *id).collect()
+    }
+
+    #[test]
+    fn only_the_last_blocks_are_tracked_on_start() {
+        let depth = TRACKING_DEPTH as u32;
+        let mut tracked = TrackedChain::new();
+        let update = follow(&mut tracked, &main_chain(depth + 20));
+        assert_eq!(ids(&update.included), (21..=depth + 20).collect::<Vec<_>>());
+        assert!(update.finalised.is_empty());
+        assert!(!update.deep_reorg);
+        assert_eq!(tracked.blocks.len(), TRACKING_DEPTH);
+    }
+
+    #[test]
+    fn the_blocks_leaving_the_tracking_depth_are_final() {
+        let depth = TRACKING_DEPTH as u32;
+        let mut tracked = TrackedChain::new();
+        follow(&mut tracked, &main_chain(depth));
+
+        let update = follow(&mut tracked, &main_chain(depth + 5));
+        assert!(update.abandoned.is_empty());
+        assert_eq!(ids(&update.included), (depth + 1..=depth + 5).collect::<Vec<_>>());
+        assert_eq!(update.finalised, vec![(1, 1, 1), (2, 2, 2), (3, 3, 3), (4, 4, 4), (5, 5, 5)]);
+        assert_eq!(tracked.blocks.len(), TRACKING_DEPTH);
+        assert_eq!(tracked.find(&5), None);
+        assert_eq!(tracked.find(&6), Some((6, 6)));
+    }
+
+    #[test]
+    fn a_switch_to_a_fork_abandons_the_blocks_above_the_fork_point() {
+        let mut main = main_chain(10);
+        let mut tracked = TrackedChain::new();
+        follow(&mut tracked, &main);
+
+        main.switch(6, vec![107, 108]);
+        assert_eq!(tracked.fork_point(&main), Some(6));
+        let update = follow(&mut tracked, &main);
+        assert!(!update.deep_reorg);
+        assert_eq!(update.abandoned, vec![10, 9, 8, 7]);
+        assert_eq!(update.included, vec![(107, 107, 7), (108, 108, 8)]);
+        assert!(update.finalised.is_empty());
+        assert_eq!(tracked.find(&8), None);
+        assert_eq!(tracked.find(&108), Some((108, 8)));
+    }
+
+    #[test]
+    fn a_switch_below_the_tracked_blocks_abandons_all_of_them() {
+        let depth = TRACKING_DEPTH as u32;
+        let mut main = main_chain(depth + 10);
+        let mut tracked = TrackedChain::new();
+        follow(&mut tracked, &main);
+
+        main.switch(5, (1006..=1000 + depth + 12).collect());
+        assert_eq!(tracked.fork_point(&main), None);
+        let update = follow(&mut tracked, &main);
+        assert!(update.deep_reorg);
+        assert_eq!(update.abandoned, (11..=depth + 10).rev().collect::<Vec<_>>());
+        assert_eq!(ids(&update.included), (1013..=1000 + depth + 12).collect::<Vec<_>>());
+        assert!(update.finalised.is_empty());
+        assert_eq!(tracked.blocks.len(), TRACKING_DEPTH);
+    }
+}
@@ -1 +1,2 @@
+pub mod signal;
 pub mod task;
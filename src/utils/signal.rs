@@ -0,0 +1,22 @@
+//! termination signals of the node
+
+use std::io;
+
+use futures::prelude::*;
+use tokio_signal;
+
+/// block until the node is asked to terminate: SIGINT (Ctrl-C) or, on
+/// unix, SIGTERM.
+pub fn wait_termination() -> io::Result<()> {
+    let ctrl_c = tokio_signal::ctrl_c().flatten_stream();
+
+    #[cfg(unix)]
+    let signals = {
+        use tokio_signal::unix::{Signal, SIGTERM};
+        ctrl_c.select(Signal::new(SIGTERM).flatten_stream().map(|_| ()))
+    };
+    #[cfg(not(unix))]
+    let signals = ctrl_c;
+
+    signals.into_future().wait().map(|_| ()).map_err(|(err, _)| err)
+}
@@ -1,61 +1,581 @@
+//! supervised tasks
+//!
+//! All the tasks of the node run on a single tokio runtime owned by
+//! `Tasks`:
+//!
+//! * the tasks processing messages are a handler called for each
+//!   message of their mailbox. The handler runs in a `blocking` section
+//!   of the runtime, it may wait on a lock or on the storage without
+//!   holding back the other tasks;
+//! * the long running blocking tasks (e.g. the bootstrap) also run in a
+//!   `blocking` section, the event driven tasks (e.g. the network) are
+//!   plain futures;
+//! * a panic of a task is captured and logged instead of taking the
+//!   other tasks down with it. After a panic of its handler, a task
+//!   processing messages goes on with the next message according to
+//!   its `RestartPolicy`;
+//! * the status of each task is kept so the node can report its health;
+//! * on shutdown the tasks processing messages drain their mailbox
+//!   before they stop.
+//!
+//! The mailboxes may be bounded: the senders then wait (the future of
+//! `send` resolves once there is room), or with `try_send` get the
+//! message back, when the task is not keeping up.
+//! Each mailbox counts its queued and processed messages.
+//!
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    atomic::AtomicUsize,
+    mpsc::TrySendError,
+    Arc, Mutex, RwLock,
+};
 use std::thread;
-use std::clone::Clone;
-use std::sync::mpsc::{Sender, Receiver, channel};
+use std::time::{Duration, Instant};
 
-#[allow(dead_code)]
-pub struct Task {
-    handler: thread::JoinHandle<()>,
-    name: &'static str,
+use futures::{future::{self, Either}, prelude::*, sync::mpsc, task};
+use tokio::runtime::Runtime;
+use tokio::timer::{Delay, Interval};
+use tokio_threadpool::blocking;
+
+/// how often the idle tasks check if the shutdown is requested
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+/// how long to wait for the tasks to stop on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// how long to wait before restarting a task that panicked
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// what to do when the handler of a task processing messages panics
+#[derive(Debug, Clone, Copy)]
+pub enum RestartPolicy {
+    /// the task is stopped
+    Never,
+    /// the task goes on with the next message, up to the given number
+    /// of panics
+    OnPanic { max_restarts: usize },
 }
 
-#[allow(dead_code)]
-pub struct TaskWithInputs<A> {
-    task: Task,
-    channel_input: TaskMessageBox<A>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskStatus {
+    Running,
+    /// the task panicked and waits to be restarted
+    Restarting,
+    /// the task returned
+    Stopped,
+    /// the task panicked and is not restarted
+    Failed,
 }
 
-pub fn task_create<F>(name: &'static str, f: F) -> Task
-  where F: FnOnce() -> (),
-        F: Send + 'static,
-{
-    let handler = thread::spawn(move || { f() });
-    Task {
-        handler: handler,
-        name: name,
+/// the health of a task, as reported by `Tasks::health`
+#[derive(Debug, Clone)]
+pub struct TaskHealth {
+    pub name: &'static str,
+    pub status: TaskStatus,
+    pub restarts: usize,
+    /// the message of the last panic of the task
+    pub last_panic: Option<String>,
+    /// the metrics of the mailbox of the tasks processing messages
+    pub mailbox: Option<MailboxStats>,
+}
+
+/// the metrics of a mailbox, as reported by `Tasks::health`
+#[derive(Debug, Clone, Copy)]
+pub struct MailboxStats {
+    /// number of messages waiting to be processed
+    pub depth: usize,
+    /// the maximum depth reached
+    pub high_water_mark: usize,
+    /// number of messages received by the task
+    pub processed: usize,
+    /// the maximum depth of a bounded mailbox
+    pub capacity: Option<usize>,
+}
+
+#[derive(Default)]
+struct MailboxMetrics {
+    depth: AtomicUsize,
+    high_water_mark: AtomicUsize,
+    processed: AtomicUsize,
+}
+
+impl MailboxMetrics {
+    fn queued(&self) {
+        let depth = self.depth.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut high = self.high_water_mark.load(Ordering::SeqCst);
+        while depth > high {
+            match self.high_water_mark.compare_exchange(high, depth, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => break,
+                Err(current) => high = current,
+            }
+        }
+    }
+
+    fn received(&self) {
+        self.depth.fetch_sub(1, Ordering::SeqCst);
+        self.processed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn stats(&self, capacity: Option<usize>) -> MailboxStats {
+        MailboxStats {
+            depth: self.depth.load(Ordering::SeqCst),
+            high_water_mark: self.high_water_mark.load(Ordering::SeqCst),
+            processed: self.processed.load(Ordering::SeqCst),
+            capacity: capacity,
+        }
     }
 }
 
-pub fn task_create_with_inputs<F, A>(name: &'static str, f: F) -> TaskWithInputs<A>
-  where F: FnOnce(Receiver<A>) -> (),
-        F: Send + 'static,
-        A: Send + 'static,
+type HealthR = Arc<RwLock<BTreeMap<&'static str, TaskHealth>>>;
+
+/// shared flag telling the tasks the node is shutting down
+#[derive(Clone)]
+pub struct Shutdown(Arc<AtomicBool>);
+
+impl Shutdown {
+    fn new() -> Self {
+        Shutdown(Arc::new(AtomicBool::new(false)))
+    }
+
+    pub fn request(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// future resolving once the shutdown is requested, for the tasks
+    /// running an event loop.
+    pub fn wait(&self) -> impl Future<Item = (), Error = ()> {
+        let shutdown = self.clone();
+        Interval::new(Instant::now(), SHUTDOWN_POLL)
+            .map_err(|err| error!("shutdown timer failed: {}", err))
+            .skip_while(move |_| future::ok(!shutdown.is_requested()))
+            .into_future()
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+}
+
+/// run a blocking function from a task of the runtime, the other tasks
+/// keep running meanwhile.
+pub fn run_blocking<F, T>(f: F) -> impl Future<Item = T, Error = ()>
+where
+    F: FnOnce() -> T,
 {
-    let (tx, rx) = channel();
+    let mut f = Some(f);
+    future::poll_fn(move || blocking(|| (f.take().expect("blocking function already called"))()))
+        .map_err(|err| error!("cannot run a blocking function: {:?}", err))
+}
 
-    let handler = thread::spawn(move || {
-        f(rx)
-    });
-    let task = Task {
-        handler: handler,
-        name: name,
-    };
-    TaskWithInputs {
-        task: task,
-        channel_input: TaskMessageBox(tx),
+/// future giving the other tasks of the runtime a chance to run, for
+/// the tasks doing a long work in several steps.
+pub fn yield_now() -> impl Future<Item = (), Error = ()> {
+    let mut yielded = false;
+    future::poll_fn(move || {
+        if yielded {
+            return Ok(Async::Ready(()));
+        }
+        yielded = true;
+        task::current().notify();
+        Ok(Async::NotReady)
+    })
+}
+
+enum MessageReceiver<A> {
+    Unbounded(mpsc::UnboundedReceiver<A>),
+    Bounded(mpsc::Receiver<A>),
+}
+
+/// the receiving end of the messages of a task. The stream ends once
+/// the shutdown is requested and all the queued messages are received.
+pub struct Mailbox<A> {
+    receiver: MessageReceiver<A>,
+    shutdown: Shutdown,
+    /// wakes the idle task up to check the shutdown, created on the
+    /// first poll as the timer is the one of the runtime
+    ticks: Option<Interval>,
+    metrics: Arc<MailboxMetrics>,
+}
+
+impl<A> Stream for Mailbox<A> {
+    type Item = A;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<A>, ()> {
+        let polled = match self.receiver {
+            MessageReceiver::Unbounded(ref mut receiver) => receiver.poll()?,
+            MessageReceiver::Bounded(ref mut receiver) => receiver.poll()?,
+        };
+        match polled {
+            Async::Ready(Some(a)) => {
+                self.metrics.received();
+                Ok(Async::Ready(Some(a)))
+            }
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => {
+                if self.shutdown.is_requested() {
+                    return Ok(Async::Ready(None));
+                }
+                let ticks = self
+                    .ticks
+                    .get_or_insert_with(|| Interval::new(Instant::now() + SHUTDOWN_POLL, SHUTDOWN_POLL));
+                while let Async::Ready(Some(_)) = ticks.poll().map_err(|err| error!("mailbox timer failed: {}", err))? {}
+                Ok(Async::NotReady)
+            }
+        }
     }
 }
 
-#[derive(Clone)]
-pub struct TaskMessageBox<A>(Sender<A>);
+enum MessageSender<A> {
+    Unbounded(mpsc::UnboundedSender<A>),
+    Bounded(mpsc::Sender<A>, usize),
+}
+
+impl<A> MessageSender<A> {
+    fn capacity(&self) -> Option<usize> {
+        match self {
+            MessageSender::Unbounded(_) => None,
+            MessageSender::Bounded(_, capacity) => Some(*capacity),
+        }
+    }
+}
+
+impl<A> Clone for MessageSender<A> {
+    fn clone(&self) -> Self {
+        match self {
+            MessageSender::Unbounded(sender) => MessageSender::Unbounded(sender.clone()),
+            MessageSender::Bounded(sender, capacity) => MessageSender::Bounded(sender.clone(), *capacity),
+        }
+    }
+}
+
+pub struct TaskMessageBox<A> {
+    name: &'static str,
+    sender: MessageSender<A>,
+    metrics: Arc<MailboxMetrics>,
+}
+
+// not derived: the messages don't need to be `Clone`
+impl<A> Clone for TaskMessageBox<A> {
+    fn clone(&self) -> Self {
+        TaskMessageBox {
+            name: self.name,
+            sender: self.sender.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
 
 impl<A> TaskMessageBox<A> {
-    pub fn send_to(self, a: A) {
-        self.0.send(a).unwrap()
+    /// send a message to the task, the future resolves once there is
+    /// room for it in a bounded mailbox. The message is dropped (and
+    /// the error logged) if the task stopped.
+    pub fn send(self, a: A) -> impl Future<Item = (), Error = ()> {
+        // counted first, the task may receive the message before the
+        // future resolves
+        self.metrics.queued();
+        let TaskMessageBox { name, sender, metrics } = self;
+        let sent = match sender {
+            MessageSender::Unbounded(sender) => {
+                Either::A(future::result(sender.unbounded_send(a).map_err(|_| ())))
+            }
+            MessageSender::Bounded(sender, _) => Either::B(sender.send(a).map(|_| ()).map_err(|_| ())),
+        };
+        sent.or_else(move |()| {
+            metrics.depth.fetch_sub(1, Ordering::SeqCst);
+            error!("task {} is not running, message dropped", name);
+            Ok(())
+        })
+    }
+
+    /// send a message to the task without waiting, the message is given
+    /// back if the mailbox is full so the caller can slow down the
+    /// source of the messages (e.g. stop reading from a peer).
+    pub fn try_send(&mut self, a: A) -> Result<(), TrySendError<A>> {
+        self.metrics.queued();
+        let result = match self.sender {
+            MessageSender::Unbounded(ref sender) => sender
+                .unbounded_send(a)
+                .map_err(|err| TrySendError::Disconnected(err.into_inner())),
+            MessageSender::Bounded(ref mut sender, _) => sender.try_send(a).map_err(|err| {
+                if err.is_full() {
+                    TrySendError::Full(err.into_inner())
+                } else {
+                    TrySendError::Disconnected(err.into_inner())
+                }
+            }),
+        };
+        if result.is_err() {
+            self.metrics.depth.fetch_sub(1, Ordering::SeqCst);
+        }
+        result
+    }
+
+    pub fn stats(&self) -> MailboxStats {
+        self.metrics.stats(self.sender.capacity())
+    }
+}
+
+fn panic_message(payload: &Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+fn set_status(health: &HealthR, name: &'static str, status: TaskStatus, panic: Option<String>) {
+    let mut health = health.write().unwrap();
+    let task = health.entry(name).or_insert(TaskHealth {
+        name: name,
+        status: status,
+        restarts: 0,
+        last_panic: None,
+        mailbox: None,
+    });
+    if status == TaskStatus::Running && task.status == TaskStatus::Restarting {
+        task.restarts += 1;
+    }
+    task.status = status;
+    if panic.is_some() {
+        task.last_panic = panic;
     }
 }
 
-impl<A> TaskWithInputs<A> {
-    pub fn get_message_box(&self) -> TaskMessageBox<A> {
-        TaskMessageBox(self.channel_input.0.clone())
+/// record the end of a task, given the result of `catch_unwind`
+fn task_ended<T>(health: &HealthR, name: &'static str, result: Result<T, Box<dyn Any + Send>>) {
+    match result {
+        Ok(_) => set_status(health, name, TaskStatus::Stopped, None),
+        Err(payload) => {
+            let message = panic_message(&payload);
+            error!("task {} panicked: {}", name, message);
+            set_status(health, name, TaskStatus::Failed, Some(message));
+        }
+    }
+}
+
+pub struct Tasks {
+    runtime: Runtime,
+    health: HealthR,
+    shutdown: Shutdown,
+    /// the metrics of the mailboxes, by task name
+    mailboxes: BTreeMap<&'static str, (Arc<MailboxMetrics>, Option<usize>)>,
+}
+
+impl Tasks {
+    pub fn new() -> Self {
+        Tasks {
+            runtime: Runtime::new().expect("cannot start the tasks runtime"),
+            health: Arc::new(RwLock::new(BTreeMap::new())),
+            shutdown: Shutdown::new(),
+            mailboxes: BTreeMap::new(),
+        }
+    }
+
+    /// the flag to watch for the tasks that don't process messages
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// create a task running a blocking function, a panic of the task
+    /// is logged and the task is not restarted.
+    pub fn task_create<F>(&mut self, name: &'static str, f: F)
+    where
+        F: FnOnce() -> (),
+        F: Send + 'static,
+    {
+        let health = self.health.clone();
+        let err_health = self.health.clone();
+        set_status(&health, name, TaskStatus::Running, None);
+        let task = run_blocking(move || panic::catch_unwind(AssertUnwindSafe(f)))
+            .map(move |result| task_ended(&health, name, result))
+            .map_err(move |()| set_status(&err_health, name, TaskStatus::Failed, None));
+        self.runtime.spawn(task);
+    }
+
+    /// create a task running the given future, a panic of the task is
+    /// logged and the task is not restarted.
+    pub fn task_create_future<F>(&mut self, name: &'static str, future: F)
+    where
+        F: Future<Item = (), Error = ()>,
+        F: Send + 'static,
+    {
+        let health = self.health.clone();
+        set_status(&health, name, TaskStatus::Running, None);
+        let task = AssertUnwindSafe(future)
+            .catch_unwind()
+            .then(move |result| Ok::<(), ()>(task_ended(&health, name, result)));
+        self.runtime.spawn(task);
+    }
+
+    /// create a task calling the handler for each message sent to the
+    /// returned message box.
+    pub fn task_create_with_inputs<F, A>(
+        &mut self,
+        name: &'static str,
+        policy: RestartPolicy,
+        handler: F,
+    ) -> TaskMessageBox<A>
+    where
+        F: FnMut(A) -> (),
+        F: Send + 'static,
+        A: Send + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded();
+        self.spawn_with_mailbox(
+            name,
+            policy,
+            MessageSender::Unbounded(tx),
+            MessageReceiver::Unbounded(rx),
+            handler,
+        )
+    }
+
+    /// like `task_create_with_inputs`, with at most about `capacity`
+    /// messages waiting in the mailbox (each sender adds one).
+    pub fn task_create_with_bounded_inputs<F, A>(
+        &mut self,
+        name: &'static str,
+        policy: RestartPolicy,
+        capacity: usize,
+        handler: F,
+    ) -> TaskMessageBox<A>
+    where
+        F: FnMut(A) -> (),
+        F: Send + 'static,
+        A: Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(capacity);
+        self.spawn_with_mailbox(
+            name,
+            policy,
+            MessageSender::Bounded(tx, capacity),
+            MessageReceiver::Bounded(rx),
+            handler,
+        )
+    }
+
+    fn spawn_with_mailbox<F, A>(
+        &mut self,
+        name: &'static str,
+        policy: RestartPolicy,
+        sender: MessageSender<A>,
+        receiver: MessageReceiver<A>,
+        handler: F,
+    ) -> TaskMessageBox<A>
+    where
+        F: FnMut(A) -> (),
+        F: Send + 'static,
+        A: Send + 'static,
+    {
+        let metrics = Arc::new(MailboxMetrics::default());
+        self.mailboxes.insert(name, (metrics.clone(), sender.capacity()));
+        let mailbox = Mailbox {
+            receiver: receiver,
+            shutdown: self.shutdown.clone(),
+            ticks: None,
+            metrics: metrics.clone(),
+        };
+        let msgbox = TaskMessageBox {
+            name: name,
+            sender: sender,
+            metrics: metrics,
+        };
+        let health = self.health.clone();
+        set_status(&health, name, TaskStatus::Running, None);
+
+        // shared with the blocking sections, one message at a time
+        let handler = Arc::new(Mutex::new(handler));
+        let restarts = Arc::new(AtomicUsize::new(0));
+        let shutdown = self.shutdown.clone();
+        let loop_health = health.clone();
+        let task = mailbox
+            .for_each(move |a| {
+                let handler = handler.clone();
+                let health = loop_health.clone();
+                let shutdown = shutdown.clone();
+                let restarts = restarts.clone();
+                run_blocking(move || {
+                    let mut handler = handler.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    panic::catch_unwind(AssertUnwindSafe(|| (&mut *handler)(a)))
+                })
+                .and_then(move |result| {
+                    let payload = match result {
+                        Ok(()) => return Either::A(future::ok(())),
+                        Err(payload) => payload,
+                    };
+                    let message = panic_message(&payload);
+                    error!("task {} panicked: {}", name, message);
+
+                    let restart = match policy {
+                        RestartPolicy::Never => false,
+                        RestartPolicy::OnPanic { max_restarts } => {
+                            restarts.load(Ordering::SeqCst) < max_restarts
+                        }
+                    };
+                    if !restart || shutdown.is_requested() {
+                        set_status(&health, name, TaskStatus::Failed, Some(message));
+                        return Either::A(future::err(()));
+                    }
+                    set_status(&health, name, TaskStatus::Restarting, Some(message));
+                    let restarts = restarts.fetch_add(1, Ordering::SeqCst) + 1;
+                    warn!("restarting task {} ({} restarts)", name, restarts);
+                    Either::B(
+                        Delay::new(Instant::now() + RESTART_DELAY)
+                            .map_err(|err| error!("restart timer failed: {}", err))
+                            .map(move |()| set_status(&health, name, TaskStatus::Running, None)),
+                    )
+                })
+            })
+            .map(move |()| set_status(&health, name, TaskStatus::Stopped, None));
+        self.runtime.spawn(task);
+
+        msgbox
+    }
+
+    pub fn health(&self) -> Vec<TaskHealth> {
+        let mut health = self.health.read().unwrap().values().cloned().collect::<Vec<_>>();
+        for task in health.iter_mut() {
+            task.mailbox = self
+                .mailboxes
+                .get(task.name)
+                .map(|(metrics, capacity)| metrics.stats(*capacity));
+        }
+        health
+    }
+
+    /// request the tasks to stop and wait for them, the tasks still
+    /// running after the timeout are dropped with the runtime.
+    pub fn shutdown(self) {
+        self.shutdown.request();
+
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        loop {
+            let running = self
+                .health()
+                .into_iter()
+                .filter(|task| task.status == TaskStatus::Running || task.status == TaskStatus::Restarting)
+                .map(|task| task.name)
+                .collect::<Vec<_>>();
+            if running.is_empty() {
+                break;
+            }
+            if Instant::now() >= deadline {
+                warn!("the tasks {:?} did not stop in time", running);
+                break;
+            }
+            debug!("waiting for the tasks {:?} to stop", running);
+            thread::sleep(SHUTDOWN_POLL);
+        }
+
+        let _ = self.runtime.shutdown_now().wait();
     }
 }